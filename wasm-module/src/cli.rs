@@ -0,0 +1,1034 @@
+use serde::Serialize;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use wasi_nn::{ExecutionTarget, GraphEncoding, TensorType};
+
+/// How the model output is checked after inference.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum Validation {
+    /// Only report the top class.
+    #[default]
+    Argmax,
+    /// Also report the sum and entropy of the softmax distribution.
+    Softmax,
+}
+
+/// Element type of the input tensor.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum InputDtype {
+    #[default]
+    F32,
+    F16,
+    /// Raw pixel values, for quantized models.
+    U8,
+}
+
+impl InputDtype {
+    pub fn parse(dtype: &str) -> Result<Self, Box<dyn Error>> {
+        match dtype {
+            "f32" => Ok(InputDtype::F32),
+            "f16" => Ok(InputDtype::F16),
+            "u8" => Ok(InputDtype::U8),
+            "i8" => Err("i8 has no wasi-nn tensor type, use u8 instead".into()),
+            other => Err(format!("Unknown input dtype: {}", other).into()),
+        }
+    }
+
+    pub fn tensor_type(self) -> TensorType {
+        match self {
+            InputDtype::F32 => TensorType::F32,
+            InputDtype::F16 => TensorType::F16,
+            InputDtype::U8 => TensorType::U8,
+        }
+    }
+
+    /// Size of one serialized tensor element in bytes.
+    pub fn byte_width(self) -> usize {
+        match self {
+            InputDtype::F32 => 4,
+            InputDtype::F16 => 2,
+            InputDtype::U8 => 1,
+        }
+    }
+}
+
+/// Reduces the precision of the f32 input values to a number of mantissa bits.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum MantissaReduction {
+    /// Round to the nearest value with this many mantissa bits.
+    Round(u32),
+    /// Drop the mantissa bits beyond this many.
+    Truncate(u32),
+}
+
+impl MantissaReduction {
+    const F32_MANTISSA_BITS: u32 = 23;
+
+    pub fn apply(self, value: f32) -> f32 {
+        let (bits, round) = match self {
+            MantissaReduction::Round(bits) => (bits, true),
+            MantissaReduction::Truncate(bits) => (bits, false),
+        };
+        if bits >= Self::F32_MANTISSA_BITS || !value.is_finite() {
+            return value;
+        }
+
+        let dropped: u32 = Self::F32_MANTISSA_BITS - bits;
+        let mut raw: u32 = value.to_bits();
+        if round {
+            // a carry out of the mantissa correctly bumps the exponent
+            raw += 1 << (dropped - 1);
+        }
+        f32::from_bits(raw & !((1 << dropped) - 1))
+    }
+}
+
+/// Order of the color channels in the input tensor.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    /// For models converted from OpenCV-based frameworks.
+    Bgr,
+}
+
+impl ChannelOrder {
+    /// Index of the red, green or blue channel that goes to each tensor channel.
+    pub fn rgb_indices(self) -> [usize; 3] {
+        match self {
+            ChannelOrder::Rgb => [0, 1, 2],
+            ChannelOrder::Bgr => [2, 1, 0],
+        }
+    }
+}
+
+/// How an image is brought to the input size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum ResizeMode {
+    /// Resize both sides to the input size, distorting non-square images.
+    #[default]
+    Stretch,
+    /// Resize the shortest side to `resize_to` keeping the aspect ratio, then take the center of
+    /// the input size, as in the usual ImageNet evaluation with 256 and 224.
+    ResizeShortestThenCenterCrop { resize_to: u32 },
+}
+
+/// How an image is turned into the input tensor. The default fits the ImageNet models, such
+/// as MobileNetV2.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PreprocessConfig {
+    /// Width and height the image is resized to, which also sets the input tensor shape.
+    pub size: (u32, u32),
+    pub resize: ResizeMode,
+    /// Per-channel mean subtracted from the values scaled to [0, 1], in RGB order.
+    pub mean: [f32; 3],
+    /// Per-channel standard deviation the values are divided by, in RGB order.
+    pub std: [f32; 3],
+    pub channel_order: ChannelOrder,
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        PreprocessConfig {
+            size: (224, 224),
+            resize: ResizeMode::default(),
+            mean: [0.485, 0.456, 0.406],
+            std: [0.229, 0.224, 0.225],
+            channel_order: ChannelOrder::default(),
+        }
+    }
+}
+
+impl PreprocessConfig {
+    /// The NCHW shape of the input tensor for one image.
+    pub fn input_shape(&self) -> Vec<usize> {
+        vec![1, 3, self.size.1 as usize, self.size.0 as usize]
+    }
+}
+
+/// Background that transparent images are composited over.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Background {
+    White,
+    Black,
+}
+
+impl Background {
+    /// The value of every color channel of the background.
+    pub fn channel_value(self) -> u8 {
+        match self {
+            Background::White => u8::MAX,
+            Background::Black => 0,
+        }
+    }
+}
+
+/// Device the model is loaded for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum Target {
+    #[default]
+    Cpu,
+    Gpu,
+    Tpu,
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Result<Self, Box<dyn Error>> {
+        match target {
+            "cpu" => Ok(Target::Cpu),
+            "gpu" => Ok(Target::Gpu),
+            "tpu" => Ok(Target::Tpu),
+            other => Err(format!("Unknown execution target: {}", other).into()),
+        }
+    }
+
+    pub fn execution_target(self) -> ExecutionTarget {
+        match self {
+            Target::Cpu => ExecutionTarget::CPU,
+            Target::Gpu => ExecutionTarget::GPU,
+            Target::Tpu => ExecutionTarget::TPU,
+        }
+    }
+}
+
+/// Format of the model file, which selects the wasi-nn backend that loads it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ModelFormat {
+    Onnx,
+    /// An OpenVINO IR model: an `.xml` graph with the `.bin` weights of the same name.
+    OpenVino,
+    TensorflowLite,
+}
+
+impl ModelFormat {
+    pub fn parse(format: &str) -> Result<Self, Box<dyn Error>> {
+        match format {
+            "onnx" => Ok(ModelFormat::Onnx),
+            "openvino" => Ok(ModelFormat::OpenVino),
+            "tflite" => Ok(ModelFormat::TensorflowLite),
+            other => Err(format!("Unknown model format: {}", other).into()),
+        }
+    }
+
+    /// The format given by the extension of the model path.
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn Error>> {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("onnx") => Ok(ModelFormat::Onnx),
+            Some("xml") | Some("bin") => Ok(ModelFormat::OpenVino),
+            Some("tflite") => Ok(ModelFormat::TensorflowLite),
+            _ => Err(format!(
+                "Cannot tell the format of {} from its extension, expected .onnx, .xml, .bin or \
+                 .tflite, or pass --model-format",
+                path
+            )
+            .into()),
+        }
+    }
+
+    pub fn graph_encoding(self) -> GraphEncoding {
+        match self {
+            ModelFormat::Onnx => GraphEncoding::Onnx,
+            ModelFormat::OpenVino => GraphEncoding::Openvino,
+            ModelFormat::TensorflowLite => GraphEncoding::TensorflowLite,
+        }
+    }
+}
+
+/// How the text report is laid out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum GroupBy {
+    /// All operations, then all phases.
+    #[default]
+    Operation,
+    /// Each phase with the operations that ran in it.
+    Phase,
+}
+
+/// How the metrics are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub enum Format {
+    /// The full report.
+    #[default]
+    Text,
+    /// A single `key=value` line, for scraping logs.
+    Oneline,
+    /// The operations, phases and total as one JSON object, for CI harnesses. It is all that
+    /// goes to stdout; the rest of the report goes to stderr.
+    Json,
+}
+
+/// Options passed to the module by the host, which forwards everything after the wasm module
+/// path as the module's arguments.
+#[derive(Debug, Clone, Serialize)]
+pub struct Options {
+    /// Write the operation timeline as an SVG waterfall chart to this path.
+    pub waterfall_svg: Option<String>,
+    pub validation: Validation,
+    /// Shape of the model output, e.g. `1,1000,1,1`, used to squeeze it down to the logits.
+    pub output_shape: Option<Vec<usize>>,
+    /// Number of times the inference is run.
+    pub iterations: usize,
+    /// Write every inference sample to this path, as JSON for `.json` paths, in the compact
+    /// binary format of `samples.rs` for `.bin` paths and as CSV otherwise.
+    pub export_samples: Option<String>,
+    /// Element type of the input tensor. Note that the ONNX backend only accepts f32.
+    pub input_dtype: InputDtype,
+    /// Measure the throughput at batch sizes 1, 2, 4, ... up to this size.
+    pub batch_sweep: Option<usize>,
+    /// Feed this pre-decoded `.npy` tensor to the model instead of the preprocessed image.
+    pub input_npy: Option<String>,
+    /// Compare the model output against this `.npy` reference output.
+    pub reference_npy: Option<String>,
+    /// Largest absolute error accepted by the reference comparison and the stability check.
+    pub tolerance: f32,
+    /// Reduce the precision of the input values before inference.
+    pub mantissa_reduction: Option<MantissaReduction>,
+    pub group_by: GroupBy,
+    pub preprocess: PreprocessConfig,
+    /// Fail unless every iteration predicts the same class with the same top score.
+    pub assert_stable: bool,
+    pub format: Format,
+    /// Decimal places of the rates, such as img/s, in the text report. Exports keep full
+    /// precision.
+    pub precision: usize,
+    /// Composite transparent images over this background. Without it the alpha channel is
+    /// ignored.
+    pub background: Option<Background>,
+    /// Report the wall-clock time spent between phases and after the last operation.
+    pub unaccounted: bool,
+    /// Report the prediction as unknown when the top softmax probability is below this.
+    pub threshold: Option<f32>,
+    /// Treat scores within this of the top score as tied with it and pick the lowest class.
+    pub tie_tolerance: Option<f32>,
+    /// Number of most probable classes printed with their softmax probabilities.
+    pub top_k: usize,
+    /// Append the metrics of the run as a SQLite script to this path.
+    pub export_sql: Option<String>,
+    /// Print the prediction as a JSON object instead of a line of text.
+    pub prediction_json: bool,
+    /// Skip reading the image and feed a constant tensor of the input shape, to measure the
+    /// inference alone.
+    pub synthetic_input: bool,
+    /// Report the first inference apart from the steady-state ones.
+    pub cold_start: bool,
+    /// `key=value` tags attached to every inference, carried into the sample exports.
+    pub tags: Vec<(String, String)>,
+    /// Write the operations completed so far to this path if the run panics.
+    pub partial_results: Option<String>,
+    /// Sleep between iterations, left out of all timings.
+    pub cooldown: Duration,
+    /// Also run every image in this directory through the model, which is loaded only once.
+    pub image_dir: Option<String>,
+    /// Sweep the image directory through each of these models and compare them.
+    pub compare_models: Option<Vec<String>>,
+    /// Ground truth classes of the images in the image directory, for the model comparison.
+    pub ground_truth: Option<String>,
+    /// Print a histogram of the inference times with this many buckets.
+    pub histogram: Option<usize>,
+    /// Class names to print with the prediction, as JSON for `.json` paths and one name per
+    /// line otherwise. `DEFAULT_LABELS` is used when it exists.
+    pub labels: Option<String>,
+    /// Write the per-image results and aggregates of the image sweep as JSON to this path.
+    pub sweep_report: Option<String>,
+    /// Identifies the run, e.g. by a git commit or build id, in the report header and the
+    /// structured exports. Defaults to the `BENCHMARK_RUN_ID` environment variable.
+    pub run_id: Option<String>,
+    /// Write the total and inference wall-clock times in nanoseconds to this path, for the host
+    /// to compare against the time it measures around the call into the module.
+    pub guest_timing: Option<String>,
+    /// Retry a failed inference this many times, with a growing backoff in between.
+    pub retries: usize,
+    /// Print a summary of a binary samples file written by `--export-samples` and exit.
+    pub summarize_samples: Option<String>,
+    /// Write what is needed to reproduce the run as JSON to this path: the arguments, the run
+    /// id, the resolved model and image with their sizes and hashes, and the environment.
+    pub bundle: Option<String>,
+    /// Execution target the model is loaded for. Loading fails if the backend can't use it.
+    pub target: Target,
+    /// Load the model once for each of these targets and compare their inference times.
+    pub targets: Option<Vec<Target>>,
+    /// Keep the input and output buffers across the images of a sweep instead of allocating
+    /// them for every image.
+    pub reuse_buffers: bool,
+    /// Read the image and the images of the image directory once before the timed work, so that
+    /// their reads don't depend on whether they are in the page cache.
+    pub warm_page_cache: bool,
+    /// List the image directory this many entries at a time, sorted within each chunk, instead
+    /// of listing and sorting it all before the sweep. Bounds the memory of huge directories at
+    /// the cost of the path order across chunks.
+    pub sweep_chunk: Option<usize>,
+    /// Fail on anything that is otherwise only a warning, such as NaN in the output.
+    pub strict: bool,
+    /// Time decoding the image re-encoded as JPEG, PNG and WebP.
+    pub decode_formats: bool,
+    /// Run the model for this long before the timed iterations, as a separate operation.
+    pub warmup: Duration,
+    /// Run the model at least this many times before the timed iterations.
+    pub warmup_iterations: usize,
+    /// Write the inference samples in the JSON format of hyperfine to this path.
+    pub export_hyperfine: Option<String>,
+    /// Also run every tensor of this `.npy` file through the model, indexing the tensors by the
+    /// first dimension of the array.
+    pub tensor_file: Option<String>,
+    /// Read length-prefixed encoded frames from this named pipe and run each through the model
+    /// until the writer closes it.
+    pub frame_pipe: Option<String>,
+    /// Stop the inference loops and sweeps once the run has taken this long, and report what
+    /// completed.
+    pub max_duration: Option<Duration>,
+    /// Measure the cost of timing an operation before the run and subtract it from the
+    /// wall-clock time of every operation.
+    pub calibrate_overhead: bool,
+    /// Print the start of every operation relative to the start of the run with the metrics.
+    pub start_offsets: bool,
+    /// Sweep the image directory at each of these square input sizes, for the trade-off
+    /// between the input size and the speed and accuracy. Needs a model with a dynamic input
+    /// size.
+    pub size_sweep: Option<Vec<u32>>,
+    /// Run synthetic operations that sleep for this long instead of loading a model, to check
+    /// the report and the exports without a model.
+    pub dry_metrics: Option<Duration>,
+    /// Print how long writing each export took.
+    pub time_exports: bool,
+    /// Write a copy of every classified image, named after its prediction, to this directory.
+    pub annotate_dir: Option<String>,
+    /// Write the predicted class, or `unknown`, to this path.
+    pub class_file: Option<String>,
+    /// Exit with the predicted class as the exit code, 255 for an unknown class.
+    pub class_exit_code: bool,
+    /// Compare the inference times against this binary samples file of a baseline run, and
+    /// exit with 1 if they regressed.
+    pub baseline: Option<String>,
+    /// Largest slowdown against the baseline, in percent, that doesn't count as a regression.
+    pub max_regression: f64,
+    /// Print the usage and exit.
+    pub help: bool,
+    /// The model, the first positional argument or `--model`.
+    pub model: String,
+    /// Format of the models, taken from their extensions when not given.
+    pub model_format: Option<ModelFormat>,
+    /// The input image, the second positional argument or `--image`. The third positional
+    /// argument sets `iterations`.
+    pub image: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            waterfall_svg: None,
+            validation: Validation::default(),
+            output_shape: None,
+            iterations: 1,
+            export_samples: None,
+            input_dtype: InputDtype::default(),
+            batch_sweep: None,
+            input_npy: None,
+            reference_npy: None,
+            tolerance: 1e-4,
+            mantissa_reduction: None,
+            group_by: GroupBy::default(),
+            preprocess: PreprocessConfig::default(),
+            assert_stable: false,
+            format: Format::default(),
+            precision: 2,
+            background: None,
+            unaccounted: false,
+            threshold: None,
+            tie_tolerance: None,
+            top_k: 5,
+            export_sql: None,
+            prediction_json: false,
+            synthetic_input: false,
+            cold_start: false,
+            tags: Vec::new(),
+            partial_results: None,
+            cooldown: Duration::ZERO,
+            image_dir: None,
+            compare_models: None,
+            ground_truth: None,
+            histogram: None,
+            labels: None,
+            sweep_report: None,
+            run_id: None,
+            guest_timing: None,
+            retries: 0,
+            summarize_samples: None,
+            bundle: None,
+            target: Target::default(),
+            targets: None,
+            reuse_buffers: false,
+            warm_page_cache: false,
+            sweep_chunk: None,
+            strict: false,
+            decode_formats: false,
+            warmup: Duration::ZERO,
+            warmup_iterations: 0,
+            export_hyperfine: None,
+            tensor_file: None,
+            frame_pipe: None,
+            max_duration: None,
+            calibrate_overhead: false,
+            start_offsets: false,
+            size_sweep: None,
+            dry_metrics: None,
+            time_exports: false,
+            annotate_dir: None,
+            class_file: None,
+            class_exit_code: false,
+            baseline: None,
+            max_regression: 5.0,
+            help: false,
+            model: DEFAULT_MODEL.to_string(),
+            model_format: None,
+            image: DEFAULT_IMAGE.to_string(),
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut options = Options::default();
+        // the first argument is the module name
+        let mut args = args.into_iter().skip(1);
+        let mut positional: usize = 0;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--waterfall-svg" => options.waterfall_svg = Some(value(&arg, args.next())?),
+                "--validation" => {
+                    options.validation = match value(&arg, args.next())?.as_str() {
+                        "argmax" => Validation::Argmax,
+                        "softmax" => Validation::Softmax,
+                        other => return Err(format!("Unknown validation mode: {}", other).into()),
+                    }
+                }
+                "--output-shape" => {
+                    options.output_shape = Some(parse_dims(&value(&arg, args.next())?)?)
+                }
+                "--iterations" => options.iterations = parse_count(&arg, args.next())?,
+                "--export-samples" => options.export_samples = Some(value(&arg, args.next())?),
+                "--input-dtype" => {
+                    options.input_dtype = InputDtype::parse(&value(&arg, args.next())?)?
+                }
+                "--batch-sweep" => options.batch_sweep = Some(parse_count(&arg, args.next())?),
+                "--input-npy" => options.input_npy = Some(value(&arg, args.next())?),
+                "--reference-npy" => options.reference_npy = Some(value(&arg, args.next())?),
+                "--tolerance" => options.tolerance = parse_float(&arg, args.next())?,
+                "--round-mantissa" => {
+                    options.mantissa_reduction = Some(MantissaReduction::Round(
+                        parse_mantissa_bits(&arg, args.next())?,
+                    ))
+                }
+                "--truncate-mantissa" => {
+                    options.mantissa_reduction = Some(MantissaReduction::Truncate(
+                        parse_mantissa_bits(&arg, args.next())?,
+                    ))
+                }
+                "--group-by" => {
+                    options.group_by = match value(&arg, args.next())?.as_str() {
+                        "operation" => GroupBy::Operation,
+                        "phase" => GroupBy::Phase,
+                        other => return Err(format!("Unknown grouping: {}", other).into()),
+                    }
+                }
+                "--size" => options.preprocess.size = parse_size(&value(&arg, args.next())?)?,
+                "--width" => {
+                    options.preprocess.size.0 = parse_size_dim(&value(&arg, args.next())?)?
+                }
+                "--height" => {
+                    options.preprocess.size.1 = parse_size_dim(&value(&arg, args.next())?)?
+                }
+                "--center-crop" => {
+                    options.preprocess.resize = ResizeMode::ResizeShortestThenCenterCrop {
+                        resize_to: parse_size_dim(&value(&arg, args.next())?)?,
+                    }
+                }
+                "--mean" => options.preprocess.mean = parse_rgb(&arg, args.next(), false)?,
+                "--std" => options.preprocess.std = parse_rgb(&arg, args.next(), true)?,
+                "--assert-stable" => options.assert_stable = true,
+                "--format" => {
+                    options.format = match value(&arg, args.next())?.as_str() {
+                        "text" => Format::Text,
+                        "oneline" => Format::Oneline,
+                        "json" => Format::Json,
+                        other => return Err(format!("Unknown format: {}", other).into()),
+                    }
+                }
+                "--precision" => options.precision = parse_precision(&arg, args.next())?,
+                "--background" => {
+                    options.background = match value(&arg, args.next())?.as_str() {
+                        "white" => Some(Background::White),
+                        "black" => Some(Background::Black),
+                        other => return Err(format!("Unknown background: {}", other).into()),
+                    }
+                }
+                "--unaccounted" => options.unaccounted = true,
+                "--threshold" => options.threshold = Some(parse_probability(&arg, args.next())?),
+                "--top-k" => options.top_k = parse_count(&arg, args.next())?,
+                "--tie-tolerance" => options.tie_tolerance = Some(parse_float(&arg, args.next())?),
+                "--export-sql" => options.export_sql = Some(value(&arg, args.next())?),
+                "--prediction-json" => options.prediction_json = true,
+                "--synthetic-input" => options.synthetic_input = true,
+                "--cold-start" => options.cold_start = true,
+                "--tag" => options.tags.push(parse_tag(&value(&arg, args.next())?)?),
+                "--partial-results" => options.partial_results = Some(value(&arg, args.next())?),
+                "--cooldown-ms" => {
+                    options.cooldown = Duration::from_millis(parse_millis(&arg, args.next())?)
+                }
+                "--image-dir" => options.image_dir = Some(value(&arg, args.next())?),
+                "--compare-models" => {
+                    options.compare_models = Some(
+                        value(&arg, args.next())?
+                            .split(',')
+                            .map(|model| model.trim().to_string())
+                            .collect(),
+                    )
+                }
+                "--size-sweep" => {
+                    options.size_sweep = Some(
+                        value(&arg, args.next())?
+                            .split(',')
+                            .map(parse_size_dim)
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "--ground-truth" => options.ground_truth = Some(value(&arg, args.next())?),
+                "--histogram" => options.histogram = Some(parse_count(&arg, args.next())?),
+                "--labels" => options.labels = Some(value(&arg, args.next())?),
+                "--sweep-report" => options.sweep_report = Some(value(&arg, args.next())?),
+                "--channel-order" => {
+                    options.preprocess.channel_order = match value(&arg, args.next())?.as_str() {
+                        "rgb" => ChannelOrder::Rgb,
+                        "bgr" => ChannelOrder::Bgr,
+                        other => return Err(format!("Unknown channel order: {}", other).into()),
+                    }
+                }
+                "--run-id" => options.run_id = Some(value(&arg, args.next())?),
+                "--guest-timing" => options.guest_timing = Some(value(&arg, args.next())?),
+                "--retries" => options.retries = parse_count(&arg, args.next())?,
+                "--summarize-samples" => {
+                    options.summarize_samples = Some(value(&arg, args.next())?)
+                }
+                "--bundle" => options.bundle = Some(value(&arg, args.next())?),
+                "--target" => options.target = Target::parse(&value(&arg, args.next())?)?,
+                "--targets" => {
+                    options.targets = Some(
+                        value(&arg, args.next())?
+                            .split(',')
+                            .map(|target| Target::parse(target.trim()))
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "--reuse-buffers" => options.reuse_buffers = true,
+                "--warm-page-cache" => options.warm_page_cache = true,
+                "--sweep-chunk" => options.sweep_chunk = Some(parse_count(&arg, args.next())?),
+                "--strict" => options.strict = true,
+                "--decode-formats" => options.decode_formats = true,
+                "--warmup" => options.warmup_iterations = parse_count(&arg, args.next())?,
+                "--warmup-ms" => {
+                    options.warmup = Duration::from_millis(parse_millis(&arg, args.next())?)
+                }
+                "--export-hyperfine" => options.export_hyperfine = Some(value(&arg, args.next())?),
+                "--tensor-file" => options.tensor_file = Some(value(&arg, args.next())?),
+                "--frame-pipe" => options.frame_pipe = Some(value(&arg, args.next())?),
+                "--max-duration-ms" => {
+                    options.max_duration =
+                        Some(Duration::from_millis(parse_millis(&arg, args.next())?))
+                }
+                "--calibrate-overhead" => options.calibrate_overhead = true,
+                "--start-offsets" => options.start_offsets = true,
+                "--dry-metrics-ms" => {
+                    options.dry_metrics =
+                        Some(Duration::from_millis(parse_millis(&arg, args.next())?))
+                }
+                "--time-exports" => options.time_exports = true,
+                "--class-file" => options.class_file = Some(value(&arg, args.next())?),
+                "--class-exit-code" => options.class_exit_code = true,
+                "--baseline" => options.baseline = Some(value(&arg, args.next())?),
+                "--max-regression" => options.max_regression = parse_float(&arg, args.next())?,
+                "--annotate-dir" => options.annotate_dir = Some(value(&arg, args.next())?),
+                "--help" | "-h" => options.help = true,
+                "--model" => options.model = value(&arg, args.next())?,
+                "--model-format" => {
+                    options.model_format = Some(ModelFormat::parse(&value(&arg, args.next())?)?)
+                }
+                "--image" => options.image = value(&arg, args.next())?,
+                _ if !arg.starts_with('-') => {
+                    match positional {
+                        0 => options.model = arg,
+                        1 => options.image = arg,
+                        2 => options.iterations = parse_count("<repeats>", Some(arg))?,
+                        _ => return Err(format!("Unexpected argument: {}", arg).into()),
+                    }
+                    positional += 1;
+                }
+                _ => return Err(format!("Unknown argument: {}", arg).into()),
+            }
+        }
+
+        if options.compare_models.is_some() && options.image_dir.is_none() {
+            return Err("--compare-models needs --image-dir".into());
+        }
+        if options.size_sweep.is_some() && options.image_dir.is_none() {
+            return Err("--size-sweep needs --image-dir".into());
+        }
+        if options.sweep_report.is_some() && options.image_dir.is_none() {
+            return Err("--sweep-report needs --image-dir".into());
+        }
+        // the ONNX backend of wasmtime-custom only takes f32 tensors
+        let model_format: Option<ModelFormat> = match options.model_format {
+            Some(format) => Some(format),
+            None => ModelFormat::from_path(&options.model).ok(),
+        };
+        if options.input_dtype != InputDtype::F32 && model_format == Some(ModelFormat::Onnx) {
+            return Err(format!(
+                "--input-dtype {} needs a backend other than ONNX, which only takes f32 tensors",
+                format!("{:?}", options.input_dtype).to_lowercase()
+            )
+            .into());
+        }
+        if options.run_id.is_none() {
+            options.run_id = std::env::var(RUN_ID_VAR).ok();
+        }
+
+        Ok(options)
+    }
+}
+
+pub const DEFAULT_MODEL: &str = "/assets/models/mobilenetv2-10.onnx";
+pub const DEFAULT_IMAGE: &str = "/assets/imgs/unseen_dog.jpg";
+pub const DEFAULT_LABELS: &str = "/assets/labels/imagenet_classes.txt";
+
+pub const USAGE: &str = "\
+Usage: wasi-nn-module [options] [model] [image] [repeats]
+
+Arguments:
+  model                       Model file [default: /assets/models/mobilenetv2-10.onnx]
+  image                       Input image [default: /assets/imgs/unseen_dog.jpg]
+  repeats                     Number of inferences, same as --iterations [default: 1]
+
+Input:
+  --model PATH, --image PATH  Same as the model and image arguments
+  --model-format onnx|openvino|tflite
+                              Model format [default: from the extension]
+  --input-npy PATH            Use a .npy tensor as the input instead of the image
+  --input-dtype f32|f16|u8    Element type of the input tensor, f32 for ONNX models
+  --size WxH, --width W, --height H
+                              Size the image is resized to [default: 224x224]
+  --round-mantissa BITS, --truncate-mantissa BITS
+                              Reduce the precision of the input values
+  --background white|black    Composite transparent images onto this background
+  --center-crop N             Resize the shortest side to N, then crop the center to the size
+  --channel-order rgb|bgr     Channel order of the input tensor
+  --mean R,G,B                Per-channel mean of the normalization [default: ImageNet]
+  --std R,G,B                 Per-channel std of the normalization [default: ImageNet]
+  --synthetic-input           Skip reading an image and use a constant tensor
+
+Measurement:
+  --iterations N              Number of inferences
+  --warmup N                  Run the model N times before the timed runs
+  --warmup-ms MS              Run the model for MS milliseconds before the timed runs
+  --cold-start                Report the first inference apart from the others
+  --warm-page-cache           Read the images once before the timed reads
+  --cooldown-ms MS            Sleep between iterations, left out of the timings
+  --retries N                 Retry a failed inference up to N times
+  --max-duration-ms MS        Stop the loops and sweeps after MS milliseconds
+  --calibrate-overhead        Subtract the cost of the measurement from the timings
+  --batch-sweep N             Measure the throughput at batch sizes up to N
+  --image-dir DIR             Also run every image in DIR through the model
+  --reuse-buffers             Reuse the input and output buffers across the images
+  --sweep-chunk N             List the image directory N entries at a time
+  --tensor-file PATH          Also run every tensor of a .npy file of shape (N, ...)
+  --frame-pipe PATH           Also run every frame written to a named pipe
+  --compare-models A,B,...    Sweep the image directory through each model
+  --size-sweep S1,S2,...      Sweep the image directory at each SxS input size
+  --target cpu|gpu|tpu        Execution target of the model [default: cpu]
+  --targets cpu,gpu,tpu       Compare the inference time on each execution target
+  --decode-formats            Compare the decode time of JPEG, PNG and WebP
+  --ground-truth PATH         Ground truth classes for the image sweep accuracy
+  --tag KEY=VALUE             Tag the inference samples, may be repeated
+  --run-id ID                 Identify the run in the report and the exports
+
+Validation:
+  --validation argmax|softmax Post-processing of the output
+  --output-shape DIMS         Expected output shape, e.g. 1,1000
+  --reference-npy PATH        Compare the output against a .npy reference
+  --tolerance VALUE           Tolerance of the output comparisons
+  --assert-stable             Fail if the prediction changes between iterations
+  --threshold P               Report the class as unknown below this probability
+  --top-k K                   Print the K most probable classes [default: 5]
+  --tie-tolerance EPS         Scores within EPS of the top score tie, lowest class wins
+  --labels PATH               Class names, as JSON or one name per line [default: ImageNet]
+  --strict                    Turn warnings into errors, exiting with 3
+  --baseline PATH             Fail if slower than a .bin samples file of a baseline run
+  --max-regression PCT        Slowdown allowed against the baseline [default: 5]
+
+Report:
+  --format text|oneline|json  Report format
+  --group-by operation|phase  Grouping of the metrics
+  --start-offsets             Print when every operation started
+  --precision DIGITS          Decimal places of the rates
+  --histogram BUCKETS         Histogram of the inference times
+  --unaccounted               Report the time not covered by any operation
+  --prediction-json           Print the prediction as JSON
+  --class-file PATH           Write the predicted class to PATH
+  --class-exit-code           Exit with the predicted class as the exit code, 255 if unknown
+  --dry-metrics-ms MS         Report synthetic MS operations instead of running a model
+
+Exports:
+  --export-samples PATH       Inference samples as .json, .bin or CSV
+  --export-sql PATH           Append the run as a SQLite script
+  --export-hyperfine PATH     Inference samples in the JSON format of hyperfine
+  --time-exports              Print how long writing each export took
+  --waterfall-svg PATH        Waterfall chart of the operations
+  --annotate-dir DIR          Copies of the images named after their predictions
+  --sweep-report PATH         Per-image results of the image sweep as JSON
+  --partial-results PATH      Write the completed operations here on a panic
+  --guest-timing PATH         Total and inference times for the host to compare
+  --summarize-samples PATH    Summarize a binary samples file and exit
+  --bundle PATH               Arguments, inputs and environment to reproduce the run
+
+  -h, --help                  Print this help
+";
+
+/// The environment variable the run id is read from when `--run-id` isn't given. The host
+/// passes it on to the module.
+pub const RUN_ID_VAR: &str = "BENCHMARK_RUN_ID";
+
+fn value(flag: &str, value: Option<String>) -> Result<String, Box<dyn Error>> {
+    value.ok_or_else(|| format!("Missing value for {}", flag).into())
+}
+
+fn parse_count(flag: &str, value: Option<String>) -> Result<usize, Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} expects a positive integer, got '{}'", flag, value).into()),
+    }
+}
+
+fn parse_millis(flag: &str, value: Option<String>) -> Result<u64, Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("{} expects a number of milliseconds, got '{}'", flag, value).into())
+}
+
+fn parse_float<T: std::str::FromStr + Copy + Into<f64>>(
+    flag: &str,
+    value: Option<String>,
+) -> Result<T, Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    match value.parse::<T>() {
+        Ok(float) if float.into().is_finite() && float.into() >= 0.0 => Ok(float),
+        _ => Err(format!("{} expects a non-negative number, got '{}'", flag, value).into()),
+    }
+}
+
+fn parse_probability(flag: &str, value: Option<String>) -> Result<f32, Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    match value.parse::<f32>() {
+        Ok(probability) if (0.0..=1.0).contains(&probability) => Ok(probability),
+        _ => Err(format!(
+            "{} expects a probability from 0 to 1, got '{}'",
+            flag, value
+        )
+        .into()),
+    }
+}
+
+fn parse_mantissa_bits(flag: &str, value: Option<String>) -> Result<u32, Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    match value.parse::<u32>() {
+        Ok(bits) if bits <= MantissaReduction::F32_MANTISSA_BITS => Ok(bits),
+        _ => Err(format!(
+            "{} expects a number of bits from 0 to 23, got '{}'",
+            flag, value
+        )
+        .into()),
+    }
+}
+
+fn parse_precision(flag: &str, value: Option<String>) -> Result<usize, Box<dyn Error>> {
+    // f64 carries no more than 17 significant digits
+    const MAX_PRECISION: usize = 17;
+
+    let value: String = self::value(flag, value)?;
+    match value.parse::<usize>() {
+        Ok(precision) if precision <= MAX_PRECISION => Ok(precision),
+        _ => Err(format!(
+            "{} expects a number of decimals from 0 to {}, got '{}'",
+            flag, MAX_PRECISION, value
+        )
+        .into()),
+    }
+}
+
+/// Parses an image size such as `299x299` into width and height.
+fn parse_size(size: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    match size.split_once('x') {
+        Some((width, height)) => Ok((parse_size_dim(width)?, parse_size_dim(height)?)),
+        None => Err(format!("Invalid size '{}', expected WIDTHxHEIGHT", size).into()),
+    }
+}
+
+fn parse_size_dim(dim: &str) -> Result<u32, Box<dyn Error>> {
+    // anything larger is almost certainly a typo and would exhaust the wasm memory
+    const MAX_DIM: u32 = 8192;
+
+    match dim.trim().parse::<u32>() {
+        Ok(dim) if dim > 0 && dim <= MAX_DIM => Ok(dim),
+        _ => Err(format!(
+            "Invalid image dimension '{}', expected 1 to {}",
+            dim, MAX_DIM
+        )
+        .into()),
+    }
+}
+
+/// Parses one value per color channel, such as `0.5,0.5,0.5`, in RGB order. A standard
+/// deviation has to be positive, since the values are divided by it.
+fn parse_rgb(
+    flag: &str,
+    value: Option<String>,
+    positive: bool,
+) -> Result<[f32; 3], Box<dyn Error>> {
+    let value: String = self::value(flag, value)?;
+    let channels: Vec<f32> = value
+        .split(',')
+        .map(|channel| channel.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("{} expects three numbers R,G,B, got '{}'", flag, value))?;
+    match channels[..] {
+        [r, g, b]
+            if channels
+                .iter()
+                .all(|c| c.is_finite() && (!positive || *c > 0.0)) =>
+        {
+            Ok([r, g, b])
+        }
+        _ => Err(format!(
+            "{} expects three {}numbers R,G,B, got '{}'",
+            flag,
+            if positive { "positive " } else { "" },
+            value
+        )
+        .into()),
+    }
+}
+
+/// Parses a tag such as `simd=on` into its key and value.
+fn parse_tag(tag: &str) -> Result<(String, String), Box<dyn Error>> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid tag '{}', expected KEY=VALUE", tag).into()),
+    }
+}
+
+/// Parses comma-separated dimensions such as `1,1000,1,1`.
+fn parse_dims(dims: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    dims.split(',')
+        .map(|dim| {
+            dim.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid dimension '{}' in '{}'", dim, dims).into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mantissa_truncation_drops_the_low_bits() {
+        assert_eq!(MantissaReduction::Truncate(1).apply(1.75), 1.5);
+        assert_eq!(MantissaReduction::Truncate(0).apply(1.75), 1.0);
+        assert_eq!(MantissaReduction::Truncate(1).apply(-1.75), -1.5);
+    }
+
+    #[test]
+    fn mantissa_rounding_goes_to_the_nearest_value() {
+        assert_eq!(MantissaReduction::Round(2).apply(1.75), 1.75);
+        assert_eq!(MantissaReduction::Round(0).apply(1.25), 1.0);
+        // the carry out of the mantissa bumps the exponent
+        assert_eq!(MantissaReduction::Round(1).apply(1.75), 2.0);
+        assert_eq!(MantissaReduction::Round(0).apply(1.9), 2.0);
+    }
+
+    #[test]
+    fn mantissa_reduction_keeps_full_precision_and_non_finite_values() {
+        let value: f32 = 0.1;
+        assert_eq!(MantissaReduction::Round(23).apply(value), value);
+        assert_eq!(MantissaReduction::Truncate(30).apply(value), value);
+        assert_eq!(
+            MantissaReduction::Round(4).apply(f32::INFINITY),
+            f32::INFINITY
+        );
+        assert!(MantissaReduction::Truncate(4).apply(f32::NAN).is_nan());
+    }
+
+    #[test]
+    fn size_is_width_by_height() {
+        assert_eq!(parse_size("224x224").unwrap(), (224, 224));
+        assert_eq!(parse_size("640 x 480").unwrap(), (640, 480));
+        assert_eq!(parse_size("1x8192").unwrap(), (1, 8192));
+    }
+
+    #[test]
+    fn size_rejects_malformed_and_out_of_range_dimensions() {
+        assert!(parse_size("224").is_err());
+        assert!(parse_size("224x").is_err());
+        assert!(parse_size("0x224").is_err());
+        assert!(parse_size("224x8193").is_err());
+        assert!(parse_size("-1x224").is_err());
+        assert!(parse_size("224x224x3").is_err());
+    }
+
+    #[test]
+    fn model_format_comes_from_the_extension() {
+        assert_eq!(
+            ModelFormat::from_path("/assets/models/mobilenetv2-10.onnx").unwrap(),
+            ModelFormat::Onnx
+        );
+        assert_eq!(
+            ModelFormat::from_path("model.xml").unwrap(),
+            ModelFormat::OpenVino
+        );
+        assert_eq!(
+            ModelFormat::from_path("model.bin").unwrap(),
+            ModelFormat::OpenVino
+        );
+        assert_eq!(
+            ModelFormat::from_path("models.v2/net.tflite").unwrap(),
+            ModelFormat::TensorflowLite
+        );
+    }
+
+    #[test]
+    fn model_format_needs_a_known_extension() {
+        assert!(ModelFormat::from_path("model").is_err());
+        assert!(ModelFormat::from_path("model.pt").is_err());
+        assert!(ModelFormat::from_path("models.onnx/net").is_err());
+    }
+
+    #[test]
+    fn input_dtype_maps_to_tensor_type_and_width() {
+        let expected = [
+            ("f32", TensorType::F32, 4),
+            ("f16", TensorType::F16, 2),
+            ("u8", TensorType::U8, 1),
+        ];
+        for (name, tensor_type, byte_width) in expected {
+            let dtype: InputDtype = InputDtype::parse(name).unwrap();
+            assert_eq!(dtype.tensor_type(), tensor_type, "{}", name);
+            assert_eq!(dtype.byte_width(), byte_width, "{}", name);
+        }
+        assert!(InputDtype::parse("i8").is_err());
+        assert!(InputDtype::parse("f64").is_err());
+    }
+}
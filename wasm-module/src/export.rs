@@ -0,0 +1,960 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::io::Write as _;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::{cli, samples, stats, BenchmarkTracker, ImageSweep, Metrics, ModelComparison};
+
+const SVG_LABEL_WIDTH: f64 = 160.0;
+const SVG_CHART_WIDTH: f64 = 640.0;
+const SVG_ROW_HEIGHT: f64 = 24.0;
+const SVG_BAR_HEIGHT: f64 = 16.0;
+
+/// Renders the operations as a waterfall chart: one `<rect>` per operation, offset by its start
+/// relative to the tracker start and proportional to its wall-clock time. Phases are drawn as
+/// lines below the operations.
+pub fn waterfall_svg(tracker: &BenchmarkTracker) -> String {
+    let start = tracker.start_metrics.timestamp;
+    let offset = |metrics: &Metrics| metrics.timestamp.saturating_duration_since(start);
+
+    let span: Duration = tracker
+        .completed_metrics
+        .iter()
+        .chain(tracker.phase_metrics.iter().map(|(_, metrics)| metrics))
+        .map(|metrics| offset(metrics) + metrics.wall_clock_time)
+        .max()
+        .unwrap_or_default();
+    let scale = |duration: Duration| {
+        if span.is_zero() {
+            0.0
+        } else {
+            duration.as_secs_f64() / span.as_secs_f64() * SVG_CHART_WIDTH
+        }
+    };
+
+    let rows = tracker.completed_metrics.len() + tracker.phase_metrics.len();
+    let width = SVG_LABEL_WIDTH + SVG_CHART_WIDTH;
+    let height = SVG_ROW_HEIGHT * (rows + 1) as f64;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" font-family="monospace" font-size="12">"#
+    );
+
+    let mut y = 0.0;
+    for metrics in &tracker.completed_metrics {
+        let _ = writeln!(
+            svg,
+            r##"<text x="4" y="{}">{}</text><rect x="{:.2}" y="{:.2}" width="{:.2}" height="{SVG_BAR_HEIGHT}" fill="#4c78a8"><title>{:?}</title></rect>"##,
+            y + SVG_BAR_HEIGHT - 4.0,
+            escape(&metrics.name),
+            SVG_LABEL_WIDTH + scale(offset(metrics)),
+            y,
+            scale(metrics.wall_clock_time).max(1.0),
+            metrics.wall_clock_time,
+        );
+        y += SVG_ROW_HEIGHT;
+    }
+
+    for phase_name in &tracker.phase_order {
+        for (_, metrics) in tracker
+            .phase_metrics
+            .iter()
+            .filter(|(name, _)| name == phase_name)
+        {
+            let x = SVG_LABEL_WIDTH + scale(offset(metrics));
+            let _ = writeln!(
+                svg,
+                r##"<text x="4" y="{}">{}</text><line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="#f58518" stroke-width="4"><title>{:?}</title></line>"##,
+                y + SVG_BAR_HEIGHT - 4.0,
+                escape(&metrics.name),
+                x,
+                y + SVG_BAR_HEIGHT / 2.0,
+                x + scale(metrics.wall_clock_time).max(1.0),
+                y + SVG_BAR_HEIGHT / 2.0,
+                metrics.wall_clock_time,
+            );
+            y += SVG_ROW_HEIGHT;
+        }
+    }
+
+    let _ = writeln!(
+        svg,
+        r#"<text x="{SVG_LABEL_WIDTH}" y="{}">0 .. {:?}</text>"#,
+        y + SVG_BAR_HEIGHT - 4.0,
+        span
+    );
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_waterfall_svg(tracker: &BenchmarkTracker, path: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(path, waterfall_svg(tracker))?;
+    Ok(())
+}
+
+/// Renders the samples as CSV with one row per iteration, with the same columns as
+/// `samples_json`.
+pub fn samples_csv(samples: &[&Metrics], run_id: Option<&str>) -> String {
+    let mut csv = String::from(
+        "run_id,iteration,wall_clock_ns,overhead_ns,user_ns,system_ns,max_rss_bytes,tags\n",
+    );
+    for (iteration, metrics) in samples.iter().enumerate() {
+        // the tags share one column as `key=value;key=value`
+        let tags: Vec<String> = metrics
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let _ = writeln!(
+            csv,
+            "\"{}\",{},{},{},{},{},{},\"{}\"",
+            run_id.unwrap_or_default().replace('"', "\"\""),
+            iteration,
+            metrics.wall_clock_time.as_nanos(),
+            metrics.overhead.as_nanos(),
+            metrics.user_time.as_nanos(),
+            metrics.system_time.as_nanos(),
+            metrics.max_rss,
+            tags.join(";").replace('"', "\"\"")
+        );
+    }
+    csv
+}
+
+/// One iteration of `samples_json`.
+#[derive(Serialize)]
+struct SampleRow<'a> {
+    run_id: Option<&'a str>,
+    iteration: usize,
+    wall_clock_ns: u128,
+    overhead_ns: u128,
+    user_ns: u128,
+    system_ns: u128,
+    max_rss_bytes: u64,
+    tags: &'a BTreeMap<String, String>,
+}
+
+/// Renders the samples as a JSON array with one object per iteration.
+pub fn samples_json(samples: &[&Metrics], run_id: Option<&str>) -> String {
+    let rows: Vec<SampleRow> = samples
+        .iter()
+        .enumerate()
+        .map(|(iteration, metrics)| SampleRow {
+            run_id,
+            iteration,
+            wall_clock_ns: metrics.wall_clock_time.as_nanos(),
+            overhead_ns: metrics.overhead.as_nanos(),
+            user_ns: metrics.user_time.as_nanos(),
+            system_ns: metrics.system_time.as_nanos(),
+            max_rss_bytes: metrics.max_rss,
+            tags: &metrics.tags,
+        })
+        .collect();
+    json_line(&rows)
+}
+
+pub fn write_samples(
+    samples: &[&Metrics],
+    run_id: Option<&str>,
+    path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let contents: Vec<u8> = if path.ends_with(".json") {
+        samples_json(samples, run_id).into_bytes()
+    } else if path.ends_with(".bin") {
+        samples::to_bytes(samples, run_id)
+    } else {
+        samples_csv(samples, run_id).into_bytes()
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Renders a single line such as
+/// `model=mobilenetv2-10 inference_ms=12.300 total_ms=845.120 rss_mb=312 class=207`, where
+/// `inference_ms` is the mean over the inference samples and the class is `unknown` when the
+/// prediction fell below the threshold. A run id is appended as `run_id=...`.
+pub fn oneline_summary(
+    model: &str,
+    total: &Metrics,
+    inference: &[&Metrics],
+    class: Option<i32>,
+    run_id: Option<&str>,
+) -> String {
+    let inference_time: Duration = inference
+        .iter()
+        .map(|metrics| metrics.wall_clock_time)
+        .sum::<Duration>()
+        .checked_div(inference.len() as u32)
+        .unwrap_or_default();
+
+    let mut summary: String = format!(
+        "model={} inference_ms={:.3} total_ms={:.3} rss_mb={} class={}",
+        model,
+        inference_time.as_secs_f64() * 1000.0,
+        total.wall_clock_time.as_secs_f64() * 1000.0,
+        total.max_rss / (1024 * 1024),
+        class.map_or("unknown".to_string(), |class| class.to_string())
+    );
+    if let Some(run_id) = run_id {
+        let _ = write!(summary, " run_id={}", run_id);
+    }
+    summary
+}
+
+/// Renders the metrics of the run as a JSON object with an `operations` array in the order
+/// they finished, a `phases` array in the order they started and the `total`. Times are in
+/// nanoseconds, with `start_offset_ns` counted from the start of the tracker, and the peak RSS
+/// is in bytes.
+pub fn metrics_json(tracker: &BenchmarkTracker, run_id: Option<&str>) -> String {
+    let start = tracker.start_metrics.timestamp;
+    let total: Metrics = tracker.get_total_metrics();
+    let report = MetricsReport {
+        run_id,
+        operations: tracker
+            .completed_metrics
+            .iter()
+            .map(|metrics| MetricsObject::new(metrics, start))
+            .collect(),
+        phases: tracker
+            .phase_order
+            .iter()
+            .filter_map(|phase_name| {
+                tracker
+                    .phase_metrics
+                    .iter()
+                    .find(|(name, _)| name == phase_name)
+                    .map(|(_, metrics)| MetricsObject::new(metrics, start))
+            })
+            .collect(),
+        total: MetricsObject::new(&total, start),
+    };
+    json_line(&report)
+}
+
+#[derive(Serialize)]
+struct MetricsReport<'a> {
+    run_id: Option<&'a str>,
+    operations: Vec<MetricsObject<'a>>,
+    phases: Vec<MetricsObject<'a>>,
+    total: MetricsObject<'a>,
+}
+
+/// An operation, phase or total of `metrics_json`.
+#[derive(Serialize)]
+struct MetricsObject<'a> {
+    name: &'a str,
+    start_offset_ns: u128,
+    wall_clock_ns: u128,
+    overhead_ns: u128,
+    user_ns: u128,
+    system_ns: u128,
+    max_rss_bytes: u64,
+    tags: &'a BTreeMap<String, String>,
+}
+
+impl<'a> MetricsObject<'a> {
+    fn new(metrics: &'a Metrics, start: Instant) -> Self {
+        Self {
+            name: &metrics.name,
+            start_offset_ns: metrics
+                .timestamp
+                .saturating_duration_since(start)
+                .as_nanos(),
+            wall_clock_ns: metrics.wall_clock_time.as_nanos(),
+            overhead_ns: metrics.overhead.as_nanos(),
+            user_ns: metrics.user_time.as_nanos(),
+            system_ns: metrics.system_time.as_nanos(),
+            max_rss_bytes: metrics.max_rss,
+            tags: &metrics.tags,
+        }
+    }
+}
+
+/// Renders a text histogram of the samples, one line per bucket with a bar proportional to its
+/// count.
+pub fn histogram_text(samples: &[&Metrics], buckets: usize) -> String {
+    const BAR_WIDTH: usize = 40;
+
+    let durations: Vec<Duration> = samples
+        .iter()
+        .map(|metrics| metrics.wall_clock_time)
+        .collect();
+    let histogram: Vec<(Duration, usize)> = stats::histogram(&durations, buckets);
+    let max_count: usize = histogram.iter().map(|(_, count)| *count).max().unwrap_or(0);
+
+    let mut text = String::new();
+    for (start, count) in histogram {
+        let bar: usize = (count * BAR_WIDTH).div_ceil(max_count.max(1));
+        let _ = writeln!(
+            text,
+            "{:>12} | {:<BAR_WIDTH$} {}",
+            format!("{:.3?}", start),
+            "#".repeat(bar),
+            count
+        );
+    }
+    text
+}
+
+/// Renders the prediction as a JSON object such as
+/// `{"image":"/assets/imgs/unseen_dog.jpg","class":207,"label":"golden retriever","score":12.3}`,
+/// with a `null` class when the prediction is unknown and a `null` label without labels.
+pub fn prediction_json(image: &str, class: Option<i32>, label: Option<&str>, score: f32) -> String {
+    #[derive(Serialize)]
+    struct Prediction<'a> {
+        image: &'a str,
+        class: Option<i32>,
+        label: Option<&'a str>,
+        score: f32,
+    }
+
+    let prediction = Prediction {
+        image,
+        class,
+        label,
+        score,
+    };
+    serde_json::to_string(&prediction).unwrap_or_default()
+}
+
+/// Writes a PNG copy of the image to `dir` with the prediction in its name, e.g.
+/// `unseen_dog.207-golden_retriever.png`, and returns its path. The `image` crate can't render
+/// text, so the prediction goes into the file name rather than onto the image.
+pub fn write_annotated(
+    image_path: &str,
+    class: i32,
+    label: Option<&str>,
+    dir: &str,
+) -> Result<String, Box<dyn Error>> {
+    let stem: &str = std::path::Path::new(image_path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("image");
+    let mut name: String = format!("{}.{}", stem, class);
+    if let Some(label) = label {
+        name.push('-');
+        name.extend(
+            label
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' }),
+        );
+    }
+
+    fs::create_dir_all(dir)?;
+    let path: String = format!("{}/{}.png", dir.trim_end_matches('/'), name);
+    image::open(image_path)?.save_with_format(&path, image::ImageFormat::Png)?;
+    Ok(path)
+}
+
+/// Renders the samples in the JSON format of `hyperfine --export-json`: a `results` array with
+/// one entry for `command`, holding the statistics and every time in seconds, so that the
+/// hyperfine scripts can plot and compare the samples.
+pub fn hyperfine_json(command: &str, samples: &[&Metrics]) -> String {
+    #[derive(Serialize)]
+    struct HyperfineExport<'a> {
+        results: [HyperfineResult<'a>; 1],
+    }
+
+    #[derive(Serialize)]
+    struct HyperfineResult<'a> {
+        command: &'a str,
+        mean: Option<f64>,
+        stddev: Option<f64>,
+        median: Option<f64>,
+        user: Option<f64>,
+        system: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+        times: Vec<f64>,
+        exit_codes: Vec<i32>,
+    }
+
+    let seconds = |duration: Option<Duration>| duration.map(|duration| duration.as_secs_f64());
+    let times: Vec<Duration> = samples
+        .iter()
+        .map(|metrics| metrics.wall_clock_time)
+        .collect();
+    let user: Vec<Duration> = samples.iter().map(|metrics| metrics.user_time).collect();
+    let system: Vec<Duration> = samples.iter().map(|metrics| metrics.system_time).collect();
+
+    json_line(&HyperfineExport {
+        results: [HyperfineResult {
+            command,
+            mean: seconds(stats::mean(&times)),
+            stddev: seconds(stats::stddev(&times)),
+            median: seconds(stats::median(&times)),
+            user: seconds(stats::mean(&user)),
+            system: seconds(stats::mean(&system)),
+            min: seconds(times.iter().min().cloned()),
+            max: seconds(times.iter().max().cloned()),
+            times: times.iter().map(Duration::as_secs_f64).collect(),
+            exit_codes: vec![0; times.len()],
+        }],
+    })
+}
+
+/// Renders an image sweep as a JSON object with an `items` array holding the prediction and
+/// inference time of every image, and an `aggregate` object with the latency statistics,
+/// throughput and accuracy of the whole sweep. A model comparison adds a `models` array.
+pub fn sweep_report_json(
+    run_id: Option<&str>,
+    sweep: &ImageSweep,
+    accuracy: Option<(usize, usize)>,
+    comparisons: Option<&[ModelComparison]>,
+) -> String {
+    #[derive(Serialize)]
+    struct SweepReport<'a> {
+        run_id: Option<&'a str>,
+        items: Vec<SweepItemObject<'a>>,
+        aggregate: SweepAggregate,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        models: Option<Vec<ModelObject<'a>>>,
+    }
+
+    #[derive(Serialize)]
+    struct SweepItemObject<'a> {
+        image: &'a str,
+        class: i32,
+        score: f32,
+        inference_ns: u128,
+    }
+
+    #[derive(Serialize)]
+    struct SweepAggregate {
+        images: usize,
+        mean_inference_ns: Option<u128>,
+        median_inference_ns: Option<u128>,
+        p99_inference_ns: Option<u128>,
+        images_per_second: f64,
+        accuracy: Option<f64>,
+    }
+
+    #[derive(Serialize)]
+    struct ModelObject<'a> {
+        model: &'a str,
+        accuracy: Option<f64>,
+        median_inference_ns: Option<u128>,
+    }
+
+    let nanos = |duration: Option<Duration>| duration.map(|duration| duration.as_nanos());
+    let ratio = |accuracy: Option<(usize, usize)>| {
+        accuracy.map(|(correct, labeled)| correct as f64 / labeled as f64)
+    };
+
+    let inference_times: Vec<Duration> = sweep.inference_times();
+    json_line(&SweepReport {
+        run_id,
+        items: sweep
+            .items
+            .iter()
+            .map(|item| SweepItemObject {
+                image: &item.path,
+                class: item.class,
+                score: item.score,
+                inference_ns: item.inference_time.as_nanos(),
+            })
+            .collect(),
+        aggregate: SweepAggregate {
+            images: sweep.items.len(),
+            mean_inference_ns: nanos(stats::mean(&inference_times)),
+            median_inference_ns: nanos(stats::median(&inference_times)),
+            p99_inference_ns: nanos(stats::percentile(&inference_times, 99.0)),
+            images_per_second: sweep.images_per_second,
+            accuracy: ratio(accuracy),
+        },
+        models: comparisons.map(|comparisons| {
+            comparisons
+                .iter()
+                .map(|comparison| ModelObject {
+                    model: &comparison.model,
+                    accuracy: ratio(comparison.accuracy),
+                    median_inference_ns: nanos(comparison.median_inference),
+                })
+                .collect()
+        }),
+    })
+}
+
+/// Environment variables recorded in the bundle. The others are left out, since the bundle is
+/// meant to be shared and could otherwise leak tokens or private paths.
+const BUNDLE_ENV_VARS: [&str; 1] = [cli::RUN_ID_VAR];
+
+/// Renders what is needed to reproduce the run as a JSON object: the module version, the
+/// arguments, the run id, the options as they were resolved, the model and image paths with the
+/// size and FNV-1a hash of each file, the wasi-nn execution target and the environment variables
+/// of `BUNDLE_ENV_VARS` that are set. The benchmark draws no random numbers, so there is no seed
+/// to record.
+pub fn bundle_json(args: &[String], options: &cli::Options) -> String {
+    #[derive(Serialize)]
+    struct Bundle<'a> {
+        version: &'a str,
+        created_at: u64,
+        run_id: Option<&'a str>,
+        arguments: &'a [String],
+        options: &'a cli::Options,
+        model: BundleFile<'a>,
+        image: BundleFile<'a>,
+        execution_target: String,
+        environment: BTreeMap<&'a str, String>,
+    }
+
+    /// An input file, with its size and hash if it could be read.
+    #[derive(Serialize)]
+    struct BundleFile<'a> {
+        path: &'a str,
+        bytes: Option<usize>,
+        fnv1a: Option<String>,
+    }
+
+    fn file(path: &str) -> BundleFile<'_> {
+        let bytes: Option<Vec<u8>> = fs::read(path).ok();
+        BundleFile {
+            path,
+            bytes: bytes.as_ref().map(Vec::len),
+            fnv1a: bytes.map(|bytes| format!("{:016x}", fnv1a(&bytes))),
+        }
+    }
+
+    let created_at: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+
+    json_line(&Bundle {
+        version: env!("CARGO_PKG_VERSION"),
+        created_at,
+        run_id: options.run_id.as_deref(),
+        arguments: args,
+        options,
+        model: file(&options.model),
+        image: file(&options.image),
+        execution_target: format!("{:?}", options.target).to_lowercase(),
+        environment: BUNDLE_ENV_VARS
+            .iter()
+            .filter_map(|key| Some((*key, std::env::var(key).ok()?)))
+            .collect(),
+    })
+}
+
+/// 64-bit FNV-1a, enough to tell whether an input file changed.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Serializes an export as one line of JSON. NaN and infinities, which JSON can't represent,
+/// become `null`.
+fn json_line<T: Serialize>(value: &T) -> String {
+    // serde_json only fails on maps with non-string keys, which no export has
+    let mut json: String = serde_json::to_string(value).unwrap_or_default();
+    json.push('\n');
+    json
+}
+
+const SQL_SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS runs (id INTEGER PRIMARY KEY, label TEXT, started_at INTEGER, model TEXT, image TEXT, options TEXT, wall_clock_ns INTEGER, user_ns INTEGER, system_ns INTEGER, max_rss_bytes INTEGER);
+CREATE TABLE IF NOT EXISTS operations (run_id INTEGER REFERENCES runs(id), position INTEGER, name TEXT, wall_clock_ns INTEGER, user_ns INTEGER, system_ns INTEGER, max_rss_bytes INTEGER);
+CREATE TABLE IF NOT EXISTS phases (run_id INTEGER REFERENCES runs(id), name TEXT, wall_clock_ns INTEGER, user_ns INTEGER, system_ns INTEGER, max_rss_bytes INTEGER);
+";
+
+/// Renders the run as a SQLite script that creates the `runs`, `operations` and `phases` tables
+/// if needed and inserts one run with its operations and phases. The run id given with
+/// `--run-id` is stored as the `label` of the run, as `id` numbers the rows, and the options as
+/// the JSON of the bundle. The scripts of several runs
+/// can be appended to one file and loaded with `sqlite3 runs.db < runs.sql`.
+pub fn run_sql(
+    tracker: &BenchmarkTracker,
+    total: &Metrics,
+    model: &str,
+    image: &str,
+    options: &cli::Options,
+) -> String {
+    let started_at: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+        .saturating_sub(total.wall_clock_time.as_secs());
+    // the rows of this run refer to the run inserted last
+    let run_id = "(SELECT max(id) FROM runs)";
+
+    let mut sql = String::from(SQL_SCHEMA);
+    sql.push_str("BEGIN;\n");
+    let _ = writeln!(
+        sql,
+        "INSERT INTO runs (label, started_at, model, image, options, wall_clock_ns, user_ns, system_ns, max_rss_bytes) VALUES ({}, {}, {}, {}, {}, {});",
+        options.run_id.as_deref().map_or("NULL".to_string(), quote),
+        started_at,
+        quote(model),
+        quote(image),
+        quote(&serde_json::to_string(options).unwrap_or_default()),
+        sql_times(total)
+    );
+    for (position, metrics) in tracker.completed_metrics.iter().enumerate() {
+        let _ = writeln!(
+            sql,
+            "INSERT INTO operations (run_id, position, name, wall_clock_ns, user_ns, system_ns, max_rss_bytes) VALUES ({}, {}, {}, {});",
+            run_id,
+            position,
+            quote(&metrics.name),
+            sql_times(metrics)
+        );
+    }
+    for (name, metrics) in &tracker.phase_metrics {
+        let _ = writeln!(
+            sql,
+            "INSERT INTO phases (run_id, name, wall_clock_ns, user_ns, system_ns, max_rss_bytes) VALUES ({}, {}, {});",
+            run_id,
+            quote(name),
+            sql_times(metrics)
+        );
+    }
+    sql.push_str("COMMIT;\n");
+    sql
+}
+
+/// Appends the SQLite script of the run to `path`.
+pub fn append_run_sql(
+    tracker: &BenchmarkTracker,
+    total: &Metrics,
+    model: &str,
+    image: &str,
+    options: &cli::Options,
+    path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(run_sql(tracker, total, model, image, options).as_bytes())?;
+    Ok(())
+}
+
+fn sql_times(metrics: &Metrics) -> String {
+    format!(
+        "{}, {}, {}, {}",
+        metrics.wall_clock_time.as_nanos(),
+        metrics.user_time.as_nanos(),
+        metrics.system_time.as_nanos(),
+        metrics.max_rss
+    )
+}
+
+/// Quotes a SQL string literal.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_json_is_one_json_object() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.start_phase("Setup");
+        tracker.start_operation("Load \"model\"");
+        tracker.tag_operation("path", "C:\\models\\net.onnx".to_string());
+        tracker.finish_operation();
+        // ends before the phase it started in, so the phases end in the opposite order
+        tracker.start_phase("Decode");
+        tracker.start_operation("Decode image");
+        tracker.finish_operation();
+        tracker.end_phase("Decode");
+        tracker.end_phase("Setup");
+
+        let json: String = metrics_json(&tracker, Some("build-1"));
+        let metrics: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(metrics["run_id"], "build-1");
+        let operations = metrics["operations"].as_array().unwrap();
+        let operation_names: Vec<&str> = operations
+            .iter()
+            .map(|operation| operation["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(operation_names, ["Load \"model\"", "Decode image"]);
+        assert_eq!(operations[0]["tags"]["path"], "C:\\models\\net.onnx");
+        let phase_names: Vec<&str> = metrics["phases"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|phase| phase["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(phase_names, tracker.phase_order);
+        assert_eq!(phase_names, ["Setup", "Decode"]);
+        assert_eq!(metrics["total"]["name"], "Total");
+        assert!(metrics["total"]["wall_clock_ns"].is_u64());
+    }
+
+    #[test]
+    fn metrics_json_without_run_id_has_null() {
+        let json: String = metrics_json(&BenchmarkTracker::new(), None);
+        let metrics: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert!(metrics["run_id"].is_null());
+        assert_eq!(metrics["operations"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn waterfall_svg_has_one_rect_per_operation() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.start_phase("Setup");
+        for name in ["Load model", "Decode <image>", "Inference"] {
+            tracker.start_operation(name);
+            tracker.finish_operation();
+        }
+        tracker.end_phase("Setup");
+
+        let svg: String = waterfall_svg(&tracker);
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<rect").count(), 3);
+        assert_eq!(svg.matches("<line").count(), 1);
+        assert!(svg.contains("Decode &lt;image&gt;"));
+    }
+
+    #[test]
+    fn oneline_summary_has_the_expected_keys() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.start_operation("Inference");
+        tracker.finish_operation();
+        let inference: Vec<&Metrics> = tracker.operation_samples("Inference");
+
+        let summary: String = oneline_summary(
+            "mobilenetv2-10",
+            &tracker.get_total_metrics(),
+            &inference,
+            Some(207),
+            Some("build-1"),
+        );
+        let keys: Vec<&str> = summary
+            .split(' ')
+            .map(|pair| pair.split_once('=').unwrap().0)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "model",
+                "inference_ms",
+                "total_ms",
+                "rss_mb",
+                "class",
+                "run_id"
+            ]
+        );
+        assert!(summary.starts_with("model=mobilenetv2-10 "));
+        assert!(summary.contains(" class=207 "));
+
+        let summary: String = oneline_summary("net", &tracker.get_total_metrics(), &[], None, None);
+        assert!(summary.ends_with(" class=unknown"));
+        assert!(summary.contains(" inference_ms=0.000 "));
+    }
+
+    #[test]
+    fn prediction_json_is_valid_json() {
+        let json: String = prediction_json(
+            "/assets/imgs/\"dog\".jpg",
+            Some(207),
+            Some("golden retriever"),
+            12.5,
+        );
+        let prediction: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(prediction["image"], "/assets/imgs/\"dog\".jpg");
+        assert_eq!(prediction["class"], 207);
+        assert_eq!(prediction["label"], "golden retriever");
+        assert_eq!(prediction["score"], 12.5);
+
+        let json: String = prediction_json("dog.jpg", None, None, f32::NAN);
+        let prediction: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(prediction["class"].is_null());
+        assert!(prediction["label"].is_null());
+        assert!(prediction["score"].is_null());
+    }
+
+    #[test]
+    fn sweep_report_has_items_and_an_aggregate() {
+        let sweep = ImageSweep {
+            items: (0..3)
+                .map(|index| crate::SweepItem {
+                    path: format!("/assets/imgs/{}.png", index),
+                    class: 207,
+                    score: 12.5,
+                    inference_time: Duration::from_millis(10 * (index + 1)),
+                })
+                .collect(),
+            images_per_second: 25.0,
+        };
+        let json: String = sweep_report_json(Some("build-1"), &sweep, Some((2, 3)), None);
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(report["run_id"], "build-1");
+        let items = report["items"].as_array().unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1]["image"], "/assets/imgs/1.png");
+        assert_eq!(items[1]["inference_ns"], 20_000_000);
+        let aggregate = &report["aggregate"];
+        assert_eq!(aggregate["images"], 3);
+        assert_eq!(aggregate["median_inference_ns"], 20_000_000);
+        assert_eq!(aggregate["images_per_second"], 25.0);
+        assert!((aggregate["accuracy"].as_f64().unwrap() - 2.0 / 3.0).abs() < 1e-9);
+        assert!(report.get("models").is_none());
+    }
+
+    #[test]
+    fn sweep_report_lists_the_compared_models() {
+        let sweep = ImageSweep {
+            items: Vec::new(),
+            images_per_second: 0.0,
+        };
+        let comparisons = [ModelComparison {
+            model: "mobilenetv2-10.onnx".to_string(),
+            accuracy: None,
+            median_inference: Some(Duration::from_millis(8)),
+        }];
+        let json: String = sweep_report_json(None, &sweep, None, Some(&comparisons));
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert!(report["aggregate"]["mean_inference_ns"].is_null());
+        assert_eq!(report["models"][0]["model"], "mobilenetv2-10.onnx");
+        assert!(report["models"][0]["accuracy"].is_null());
+        assert_eq!(report["models"][0]["median_inference_ns"], 8_000_000);
+    }
+
+    #[test]
+    fn bundle_json_has_the_required_fields() {
+        let model = std::env::temp_dir().join(format!("bundle-{}.onnx", std::process::id()));
+        fs::write(&model, b"a").unwrap();
+        let options = cli::Options {
+            model: model.to_str().unwrap().to_string(),
+            image: "/nonexistent/image.jpg".to_string(),
+            run_id: Some("build-1".to_string()),
+            ..cli::Options::default()
+        };
+        let args: Vec<String> = vec!["wasi-nn-module".to_string(), "--run-id".to_string()];
+
+        let json: String = bundle_json(&args, &options);
+        fs::remove_file(&model).unwrap();
+        let bundle: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(bundle["version"], env!("CARGO_PKG_VERSION"));
+        assert!(bundle["created_at"].is_u64());
+        assert_eq!(bundle["run_id"], "build-1");
+        assert_eq!(bundle["arguments"], serde_json::json!(args));
+        assert_eq!(bundle["options"]["model"], options.model.as_str());
+        assert_eq!(bundle["options"]["iterations"], options.iterations);
+        assert_eq!(
+            bundle["options"]["preprocess"]["size"],
+            serde_json::json!([224, 224])
+        );
+        assert_eq!(bundle["model"]["path"], options.model.as_str());
+        assert_eq!(bundle["model"]["bytes"], 1);
+        // FNV-1a of the single byte "a"
+        assert_eq!(bundle["model"]["fnv1a"], "af63dc4c8601ec8c");
+        assert!(bundle["image"]["bytes"].is_null());
+        assert!(bundle["image"]["fnv1a"].is_null());
+        assert_eq!(bundle["execution_target"], "cpu");
+        assert!(bundle["environment"].is_object());
+    }
+
+    #[test]
+    fn hyperfine_json_has_the_hyperfine_fields() {
+        let mut tracker = BenchmarkTracker::new();
+        for _ in 0..3 {
+            tracker.start_operation("Inference");
+            tracker.finish_operation();
+        }
+        let samples: Vec<&Metrics> = tracker.operation_samples("Inference");
+
+        let json: String = hyperfine_json("wasi-nn-module --iterations 3", &samples);
+        let export: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let results = export["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!(result["command"], "wasi-nn-module --iterations 3");
+        for field in ["mean", "stddev", "median", "min", "max", "user", "system"] {
+            assert!(result[field].is_f64(), "{}", field);
+        }
+        assert_eq!(result["times"].as_array().unwrap().len(), 3);
+        assert_eq!(result["exit_codes"], serde_json::json!([0, 0, 0]));
+        assert!(result["min"].as_f64() <= result["max"].as_f64());
+    }
+
+    #[test]
+    fn two_runs_append_to_one_script() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.start_phase("Setup");
+        tracker.start_operation("Load model");
+        tracker.finish_operation();
+        tracker.end_phase("Setup");
+        let total: Metrics = tracker.get_total_metrics();
+        let options = cli::Options {
+            run_id: Some("it's build-1".to_string()),
+            ..cli::Options::default()
+        };
+
+        let path = std::env::temp_dir().join(format!("runs-{}.sql", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path: &str = path.to_str().unwrap();
+        for _ in 0..2 {
+            append_run_sql(&tracker, &total, "net.onnx", "dog.jpg", &options, path).unwrap();
+        }
+        let sql: String = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(sql.matches("INSERT INTO runs").count(), 2);
+        assert_eq!(sql.matches("INSERT INTO operations").count(), 2);
+        assert_eq!(sql.matches("INSERT INTO phases").count(), 2);
+        assert_eq!(sql.matches("COMMIT;").count(), 2);
+        assert!(sql.contains("'it''s build-1'"));
+        // the options column holds the JSON of the options, its quotes doubled for SQL
+        let options_json: String = serde_json::to_string(&options).unwrap();
+        assert!(sql.contains(&quote(&options_json)));
+        assert!(options_json.starts_with('{'));
+    }
+
+    #[test]
+    fn samples_csv_and_json_have_a_row_per_iteration() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.overhead = Duration::from_nanos(1);
+        for _ in 0..20 {
+            tracker.start_operation("Inference");
+            tracker.finish_operation();
+        }
+        let samples: Vec<&Metrics> = tracker.operation_samples("Inference");
+
+        let csv: String = samples_csv(&samples, Some("build-1"));
+        let mut lines = csv.lines();
+        let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(rows.len(), 20);
+        assert!(rows
+            .iter()
+            .all(|row| row.split(',').count() == header.len()));
+
+        let json: String = samples_json(&samples, Some("build-1"));
+        let json_rows: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(json_rows.len(), 20);
+        let json_columns: Vec<&String> = json_rows[0].as_object().unwrap().keys().collect();
+        let mut csv_columns: Vec<&str> = header.clone();
+        csv_columns.sort();
+        assert_eq!(json_columns, csv_columns);
+
+        let overhead: usize = header
+            .iter()
+            .position(|column| *column == "overhead_ns")
+            .unwrap();
+        assert_eq!(
+            rows[19].split(',').nth(overhead).unwrap(),
+            json_rows[19]["overhead_ns"].to_string()
+        );
+    }
+}
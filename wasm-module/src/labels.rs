@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use serde_json::Value;
+
+/// Reads class names indexed from 0, from a JSON file for `.json` paths and from a text file
+/// with one name per line otherwise.
+pub fn read_labels(path: &str) -> Result<HashMap<usize, String>, Box<dyn Error>> {
+    let contents: String = fs::read_to_string(path)?;
+    if path.ends_with(".json") {
+        parse_json_labels(&contents).map_err(|error| format!("{}: {}", path, error).into())
+    } else {
+        Ok(contents
+            .lines()
+            .map(|line| line.trim().to_string())
+            .enumerate()
+            .collect())
+    }
+}
+
+/// Parses labels given either as an array of names or as an object mapping indices to names,
+/// such as `{"0": "tench", ...}`. A name can also be the last string of an array, as in
+/// `{"0": ["n01440764", "tench"], ...}`.
+pub fn parse_json_labels(json: &str) -> Result<HashMap<usize, String>, Box<dyn Error>> {
+    let labels: Value = serde_json::from_str(json)?;
+    let entries: Vec<(usize, &Value)> = match &labels {
+        Value::Array(names) => names.iter().enumerate().collect(),
+        Value::Object(names) => names
+            .iter()
+            .map(|(index, name)| match index.parse::<usize>() {
+                Ok(index) => Ok((index, name)),
+                Err(_) => Err(format!("Invalid label index '{}'", index)),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err("The labels must be a JSON array or object".into()),
+    };
+
+    entries
+        .into_iter()
+        .map(|(index, name)| match label_name(name) {
+            Some(name) => Ok((index, name)),
+            None => Err(format!("Invalid label for index {}", index).into()),
+        })
+        .collect()
+}
+
+fn label_name(name: &Value) -> Option<String> {
+    match name {
+        Value::String(name) => Some(name.clone()),
+        Value::Array(parts) => parts.last()?.as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_and_object_labels_give_the_same_names() {
+        let from_array = parse_json_labels(r#"["tench", "goldfish"]"#).unwrap();
+        let from_object = parse_json_labels(r#"{"1": "goldfish", "0": "tench"}"#).unwrap();
+        assert_eq!(from_array, from_object);
+        assert_eq!(from_array[&1], "goldfish");
+    }
+
+    #[test]
+    fn object_labels_can_hold_synset_pairs() {
+        let labels = parse_json_labels(r#"{"0": ["n01440764", "tench"]}"#).unwrap();
+        assert_eq!(labels[&0], "tench");
+    }
+
+    #[test]
+    fn malformed_labels_are_rejected() {
+        assert!(parse_json_labels(r#"{"zero": "tench"}"#).is_err());
+        assert!(parse_json_labels(r#"[1, 2]"#).is_err());
+        assert!(parse_json_labels(r#""tench""#).is_err());
+    }
+}
@@ -1,56 +1,133 @@
 use image::{ImageBuffer, Pixel, Rgba};
-use libc::rusage;
 use ndarray::s;
 use std::error::Error;
 use std::fs;
+use std::io::Read;
 use std::{
-    cmp::Ordering,
-    collections::HashMap,
-    fmt::Debug,
-    num::NonZero,
-    ops::RangeFrom,
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Write as _},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Mutex,
     time::{Duration, Instant},
 };
-use wasi_nn::{ExecutionTarget, Graph, GraphBuilder, GraphEncoding, GraphExecutionContext};
+use wasi_nn::{ExecutionTarget, Graph, GraphBuilder, GraphExecutionContext, TensorType};
+
+/// Set with `--format json`, whose stdout is the metrics object alone.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// `print!` for the human-readable output, which goes to stderr with `--format json`.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if crate::JSON_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+            eprint!($($arg)*);
+        } else {
+            print!($($arg)*);
+        }
+    };
+}
+
+/// `println!` for the human-readable output, which goes to stderr with `--format json`.
+macro_rules! reportln {
+    ($($arg:tt)*) => {
+        if crate::JSON_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod allocations;
+mod cli;
+mod export;
+mod labels;
+mod npy;
+mod onnx;
+mod postprocess;
+mod samples;
+mod stats;
+
+/// Resource usage of the host process, as written by the `getrusage` import of
+/// `wasmtime-custom`. WASI has no `getrusage`, and the module shares the host's process anyway.
+#[repr(C)]
+#[derive(Default)]
+struct HostRusage {
+    user_time_us: u64,
+    system_time_us: u64,
+    max_rss_bytes: u64,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "benchmark")]
+extern "C" {
+    #[link_name = "getrusage"]
+    fn host_getrusage(usage: *mut HostRusage) -> i32;
+}
+
+/// Native builds, such as the unit tests, run without the host and report no usage.
+#[cfg(not(target_arch = "wasm32"))]
+unsafe fn host_getrusage(_usage: *mut HostRusage) -> i32 {
+    -1
+}
 
 #[derive(Debug, Clone)]
 struct Metrics {
     name: String,
+    /// When the measured interval started.
     timestamp: Instant,
     wall_clock_time: Duration,
     user_time: Duration,
     system_time: Duration,
+    /// Peak resident set size of the host process in bytes at the end of the interval. The
+    /// peak only grows, so an operation reports the peak reached by its end rather than a
+    /// difference, and combined measurements report the larger peak.
     max_rss: u64,
     cpu_usage: f32,
+    /// Key/value metadata of an operation, e.g. `batch_size=8`, carried into the exports.
+    tags: BTreeMap<String, String>,
+    /// Allocations and allocated bytes, counted with the `counting-allocator` feature.
+    allocations: u64,
+    allocated_bytes: u64,
+    /// Calibrated cost of the measurement itself, already subtracted from `wall_clock_time`.
+    /// The raw wall-clock time is the sum of the two.
+    overhead: Duration,
 }
 
 impl Metrics {
     fn current(name: String) -> Self {
+        let mut usage: HostRusage = HostRusage::default();
+        // all zero if the host couldn't get the usage
         unsafe {
-            let mut usage: rusage = std::mem::zeroed();
-            usage.ru_utime.tv_sec = 1;
-            usage.ru_utime.tv_usec = 0;
-            usage.ru_stime.tv_sec = 1;
-            usage.ru_stime.tv_usec = 0;
-
-            // getrusage(0, &mut usage);
-
-            let user_time: Duration = Duration::from_secs(usage.ru_utime.tv_sec as u64)
-                + Duration::from_micros(usage.ru_utime.tv_usec as u64);
-
-            let system_time: Duration = Duration::from_secs(usage.ru_stime.tv_sec as u64)
-                + Duration::from_micros(usage.ru_stime.tv_usec as u64);
-
-            let cpu_usage: f32 = 0.0;
-            Self {
-                name,
-                timestamp: Instant::now(),
-                wall_clock_time: Duration::default(),
-                user_time,
-                system_time,
-                max_rss: 0 as u64,
-                cpu_usage,
-            }
+            host_getrusage(&mut usage);
+        }
+
+        let user_time: Duration = Duration::from_micros(usage.user_time_us);
+        let system_time: Duration = Duration::from_micros(usage.system_time_us);
+
+        let cpu_usage: f32 = 0.0;
+        let (allocations, allocated_bytes) = allocations::allocated();
+        Self {
+            name,
+            timestamp: Instant::now(),
+            wall_clock_time: Duration::default(),
+            user_time,
+            system_time,
+            max_rss: usage.max_rss_bytes,
+            cpu_usage,
+            tags: BTreeMap::new(),
+            allocations,
+            allocated_bytes,
+            overhead: Duration::ZERO,
+        }
+    }
+
+    /// User and system time as a percentage of the wall-clock time, 0 for an empty interval.
+    fn cpu_usage(wall_clock_time: Duration, user_time: Duration, system_time: Duration) -> f32 {
+        if wall_clock_time.as_secs_f32() > 0.0 {
+            let cpu_time: f32 = (user_time + system_time).as_secs_f32();
+            (cpu_time / wall_clock_time.as_secs_f32()) * 100.0
+        } else {
+            0.0
         }
     }
 
@@ -59,44 +136,52 @@ impl Metrics {
         let user_time: Duration = self.user_time - prev.user_time;
         let system_time: Duration = self.system_time - prev.system_time;
 
-        let cpu_usage: f32 = if wall_clock_time.as_secs_f32() > 0.0 {
-            let cpu_time: f32 = (user_time + system_time).as_secs_f32();
-            (cpu_time / wall_clock_time.as_secs_f32()) * 100.0
-        } else {
-            0.0
-        };
+        let cpu_usage: f32 = Self::cpu_usage(wall_clock_time, user_time, system_time);
 
         Self {
             name: self.name.clone(),
-            timestamp: self.timestamp,
+            timestamp: prev.timestamp,
             wall_clock_time,
             user_time,
             system_time,
-            max_rss: self.max_rss - prev.max_rss,
+            // a difference of two high-water marks means nothing, the peak at the end does
+            max_rss: self.max_rss,
             cpu_usage,
+            tags: prev.tags.clone(),
+            allocations: self.allocations - prev.allocations,
+            allocated_bytes: self.allocated_bytes - prev.allocated_bytes,
+            overhead: Duration::ZERO,
         }
     }
 
+    /// Sums the times of two measurements. The combined measurement starts at the earlier of the
+    /// two timestamps, so the span of a phase begins where its first operation (or the phase
+    /// itself) started regardless of the order in which measurements are combined. The CPU usage
+    /// is recomputed from the summed times, as averaging the two usages would weigh a short
+    /// operation as much as a long one.
     fn combine(&self, other: &Self) -> Self {
         let combined_wall_clock = self.wall_clock_time + other.wall_clock_time;
         let combined_user_time = self.user_time + other.user_time;
         let combined_system_time = self.system_time + other.system_time;
 
-        let cpu_usage = if combined_wall_clock.as_secs_f32() > 0.0 {
-            let cpu_time = (combined_user_time + combined_system_time).as_secs_f32();
-            (cpu_time / combined_wall_clock.as_secs_f32()) * 100.0
-        } else {
-            0.0
-        };
+        let cpu_usage = Self::cpu_usage(
+            combined_wall_clock,
+            combined_user_time,
+            combined_system_time,
+        );
 
         Self {
             name: self.name.clone(),
-            timestamp: self.timestamp,
+            timestamp: self.timestamp.min(other.timestamp),
             wall_clock_time: combined_wall_clock,
             user_time: combined_user_time,
             system_time: combined_system_time,
             max_rss: self.max_rss.max(other.max_rss),
             cpu_usage,
+            tags: self.tags.clone(),
+            allocations: self.allocations + other.allocations,
+            allocated_bytes: self.allocated_bytes + other.allocated_bytes,
+            overhead: self.overhead + other.overhead,
         }
     }
 }
@@ -105,10 +190,25 @@ impl std::fmt::Display for Metrics {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "============= {} Metrics =============", self.name)?;
         writeln!(f, "Wall Clock Time: {:?}", self.wall_clock_time)?;
+        if !self.overhead.is_zero() {
+            writeln!(
+                f,
+                "Raw Wall Clock Time: {:?} (measurement overhead {:?})",
+                self.wall_clock_time + self.overhead,
+                self.overhead
+            )?;
+        }
         writeln!(f, "User time: {:?}", self.user_time)?;
         writeln!(f, "System time: {:?}", self.system_time)?;
         writeln!(f, "Max RSS: {} bytes", self.max_rss)?;
         writeln!(f, "CPU Usage: {}%", self.cpu_usage)?;
+        if cfg!(feature = "counting-allocator") {
+            writeln!(
+                f,
+                "Allocated: {} bytes in {} allocations",
+                self.allocated_bytes, self.allocations
+            )?;
+        }
         writeln!(f, "=======================================")
     }
 }
@@ -116,43 +216,137 @@ impl std::fmt::Display for Metrics {
 #[derive(Debug)]
 struct BenchmarkTracker {
     start_metrics: Metrics,
-    current_operation: Option<Metrics>,
+    /// The operations in progress, innermost last. `finish_operation` finishes the innermost.
+    current_operations: Vec<Metrics>,
     completed_metrics: Vec<Metrics>,
     active_phases: HashMap<String, Metrics>,
     phase_metrics: Vec<(String, Metrics)>,
     phase_order: Vec<String>,
+    /// Indices into `completed_metrics` of the operations finished while each phase was active.
+    phase_operations: HashMap<String, Vec<usize>>,
+    /// Also record completed operations in `PARTIAL_METRICS` for the panic hook.
+    record_partial: bool,
+    /// Time spent in `sleep_untracked`, which is left out of the total.
+    untracked_time: Duration,
+    /// Wall-clock budget of the whole run, checked by the loops between iterations.
+    budget: Option<Duration>,
+    /// Set once a loop stopped early because the budget ran out.
+    budget_exceeded: bool,
+    /// Print when every operation started, relative to the start of the tracker.
+    show_start_offsets: bool,
+    /// Wall-clock time an empty operation measures, subtracted from every operation once
+    /// calibrated with `calibrate_overhead`.
+    overhead: Duration,
+}
+
+/// Finishes the operation started by `BenchmarkTracker::operation` on drop. It derefs to the
+/// tracker, which stays usable while the operation runs.
+struct OperationGuard<'a> {
+    tracker: &'a mut BenchmarkTracker,
+}
+
+impl std::ops::Deref for OperationGuard<'_> {
+    type Target = BenchmarkTracker;
+
+    fn deref(&self) -> &BenchmarkTracker {
+        self.tracker
+    }
 }
 
+impl std::ops::DerefMut for OperationGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BenchmarkTracker {
+        self.tracker
+    }
+}
+
+impl Drop for OperationGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.finish_operation();
+    }
+}
+
+/// Operations completed so far, kept outside of the tracker so that the panic hook can write
+/// them out.
+static PARTIAL_METRICS: Mutex<Vec<Metrics>> = Mutex::new(Vec::new());
+
 impl BenchmarkTracker {
     fn new() -> Self {
         Self {
             start_metrics: Metrics::current("Total".to_string()),
-            current_operation: None,
+            current_operations: Vec::new(),
             completed_metrics: Vec::new(),
             active_phases: HashMap::new(),
             phase_metrics: Vec::new(),
             phase_order: Vec::new(),
+            phase_operations: HashMap::new(),
+            record_partial: false,
+            untracked_time: Duration::default(),
+            budget: None,
+            budget_exceeded: false,
+            show_start_offsets: false,
+            overhead: Duration::ZERO,
         }
     }
 
     fn start_operation(&mut self, name: &str) {
-        self.current_operation = Some(Metrics::current(name.to_string()));
+        self.start_tagged_operation(name, &[]);
+    }
+
+    /// Starts an operation that is finished when the returned guard is dropped, also when the
+    /// scope is left early with `?`.
+    fn operation(&mut self, name: &str) -> OperationGuard<'_> {
+        self.start_operation(name);
+        OperationGuard { tracker: self }
+    }
+
+    /// Starts an operation, nested in the operation in progress if there is one. A nested
+    /// operation is tagged with the name of its parent.
+    fn start_tagged_operation(&mut self, name: &str, tags: &[(String, String)]) {
+        let mut metrics: Metrics = Metrics::current(name.to_string());
+        metrics.tags.extend(tags.iter().cloned());
+        if let Some(parent) = self.current_operations.last() {
+            metrics
+                .tags
+                .insert("parent".to_string(), parent.name.clone());
+        }
+        self.current_operations.push(metrics);
     }
 
     fn finish_operation(&mut self) {
-        if let Some(start_metrics) = self.current_operation.take() {
+        if let Some(start_metrics) = self.current_operations.pop() {
             self.finish_operation_internal(start_metrics);
         }
     }
 
     fn finish_operation_internal(&mut self, start_metrics: Metrics) {
         let end_metrics: Metrics = Metrics::current(start_metrics.name.clone());
-        let diff_metrics: Metrics = end_metrics.diff(&start_metrics);
-
+        let mut diff_metrics: Metrics = end_metrics.diff(&start_metrics);
+        diff_metrics.overhead = self.overhead.min(diff_metrics.wall_clock_time);
+        diff_metrics.wall_clock_time -= diff_metrics.overhead;
+        diff_metrics.cpu_usage = Metrics::cpu_usage(
+            diff_metrics.wall_clock_time,
+            diff_metrics.user_time,
+            diff_metrics.system_time,
+        );
+
+        let index: usize = self.completed_metrics.len();
         self.completed_metrics.push(diff_metrics.clone());
+        if self.record_partial {
+            if let Ok(mut partial_metrics) = PARTIAL_METRICS.lock() {
+                partial_metrics.push(diff_metrics.clone());
+            }
+        }
 
-        for (_, phase_metrics) in self.active_phases.iter_mut() {
-            *phase_metrics = phase_metrics.combine(&diff_metrics);
+        // a nested operation is already part of its parent's time
+        let nested: bool = !self.current_operations.is_empty();
+        for (phase_name, phase_metrics) in self.active_phases.iter_mut() {
+            if !nested {
+                *phase_metrics = phase_metrics.combine(&diff_metrics);
+            }
+            self.phase_operations
+                .entry(phase_name.clone())
+                .or_default()
+                .push(index);
         }
     }
 
@@ -165,6 +359,10 @@ impl BenchmarkTracker {
             system_time: Duration::default(),
             max_rss: 0,
             cpu_usage: 0.0,
+            tags: BTreeMap::new(),
+            allocations: 0,
+            allocated_bytes: 0,
+            overhead: Duration::ZERO,
         };
 
         self.active_phases
@@ -181,20 +379,173 @@ impl BenchmarkTracker {
         }
     }
 
+    /// Total wall-clock time of the named operations.
+    fn operations_time(&self, names: &[&str]) -> Duration {
+        self.completed_metrics
+            .iter()
+            .filter(|metrics| names.contains(&metrics.name.as_str()))
+            .map(|metrics| metrics.wall_clock_time)
+            .sum()
+    }
+
+    /// All completed measurements of the named operation, in the order they were recorded.
+    fn operation_samples(&self, name: &str) -> Vec<&Metrics> {
+        self.completed_metrics
+            .iter()
+            .filter(|metrics| metrics.name == name)
+            .collect()
+    }
+
+    /// Wall-clock time not covered by any operation: between the last operation of each phase
+    /// and the start of the next phase, and between the last operation and the end of `total`.
+    fn unaccounted_time(&self, total: &Metrics) -> Vec<(String, Duration)> {
+        let end = |metrics: &Metrics| metrics.timestamp + metrics.wall_clock_time;
+        let phase_start = |phase_name: &String| {
+            self.phase_metrics
+                .iter()
+                .find(|(name, _)| name == phase_name)
+                .map(|(_, metrics)| metrics.timestamp)
+        };
+        let phase_end = |phase_name: &String| {
+            self.phase_operations
+                .get(phase_name)
+                .into_iter()
+                .flatten()
+                .map(|index| end(&self.completed_metrics[*index]))
+                .max()
+                .or_else(|| phase_start(phase_name))
+        };
+
+        let mut gaps: Vec<(String, Duration)> = Vec::new();
+        for phases in self.phase_order.windows(2) {
+            if let (Some(previous_end), Some(next_start)) =
+                (phase_end(&phases[0]), phase_start(&phases[1]))
+            {
+                gaps.push((
+                    format!("{} -> {}", phases[0], phases[1]),
+                    next_start.saturating_duration_since(previous_end),
+                ));
+            }
+        }
+
+        if let Some(last_end) = self.completed_metrics.iter().map(end).max() {
+            gaps.push((
+                "After the last operation".to_string(),
+                (end(total) + self.untracked_time).saturating_duration_since(last_end),
+            ));
+        }
+
+        gaps
+    }
+
+    /// Sleeps and leaves the sleep out of the total and of the operations in progress.
+    fn sleep_untracked(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+        self.untracked_time += duration;
+        for current_operation in self.current_operations.iter_mut() {
+            current_operation.timestamp += duration;
+        }
+    }
+
+    /// Measures the mean wall-clock time of `rounds` empty operations, the cost of the two
+    /// `getrusage` calls and clock reads around every operation, to subtract it from the
+    /// operations finished afterwards.
+    fn calibrate_overhead(&mut self, rounds: usize) {
+        let mut total = Duration::ZERO;
+        for _ in 0..rounds {
+            let start_metrics: Metrics = Metrics::current(String::new());
+            total += Metrics::current(String::new())
+                .diff(&start_metrics)
+                .wall_clock_time;
+        }
+        self.overhead = total / rounds.max(1) as u32;
+    }
+
+    /// Whether the budget ran out, in which case the caller should stop before its next
+    /// iteration or item.
+    fn over_budget(&mut self) -> bool {
+        let over_budget: bool = self
+            .budget
+            .is_some_and(|budget| self.start_metrics.timestamp.elapsed() >= budget);
+        self.budget_exceeded |= over_budget;
+        over_budget
+    }
+
+    /// Adds a tag to the innermost operation in progress.
+    fn tag_operation(&mut self, key: &str, value: String) {
+        if let Some(current_operation) = self.current_operations.last_mut() {
+            current_operation.tags.insert(key.to_string(), value);
+        }
+    }
+
+    /// Problems with the accounting: a phase longer than the total, or operations that add up
+    /// to more than the total. Nested operations are left out of the sum, as their parents
+    /// include them.
+    fn check_consistency(&self, total: &Metrics) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        for (phase_name, metrics) in &self.phase_metrics {
+            if metrics.wall_clock_time > total.wall_clock_time {
+                problems.push(format!(
+                    "{} takes {:?}, longer than the total {:?}",
+                    phase_name, metrics.wall_clock_time, total.wall_clock_time
+                ));
+            }
+        }
+
+        let operations: Duration = self
+            .completed_metrics
+            .iter()
+            .filter(|metrics| !metrics.tags.contains_key("parent"))
+            .map(|metrics| metrics.wall_clock_time)
+            .sum();
+        if operations > total.wall_clock_time {
+            problems.push(format!(
+                "the operations take {:?}, longer than the total {:?}",
+                operations, total.wall_clock_time
+            ));
+        }
+        problems
+    }
+
     fn get_total_metrics(&self) -> Metrics {
-        let current: Metrics = Metrics::current("Total".to_string());
+        let mut current: Metrics = Metrics::current("Total".to_string());
+        current.timestamp = current
+            .timestamp
+            .checked_sub(self.untracked_time)
+            .unwrap_or(self.start_metrics.timestamp);
         current.diff(&self.start_metrics)
     }
 
+    /// A completed operation as printed, preceded by its start offset with
+    /// `show_start_offsets`.
+    fn operation_text(&self, metrics: &Metrics) -> String {
+        let mut text = String::new();
+        if self.show_start_offsets {
+            let _ = writeln!(
+                text,
+                "Start offset: +{:?}",
+                metrics
+                    .timestamp
+                    .saturating_duration_since(self.start_metrics.timestamp)
+            );
+        }
+        let _ = write!(text, "{}", metrics);
+        text
+    }
+
+    fn print_operation(&self, metrics: &Metrics) {
+        report!("{}", self.operation_text(metrics));
+    }
+
     fn print_all_metrics(&self) {
         let total: Metrics = self.get_total_metrics();
 
         for metrics in &self.completed_metrics {
-            print!("{}", metrics);
+            self.print_operation(metrics);
         }
 
         if !self.phase_metrics.is_empty() {
-            println!("\n=========== Phase Metrics ===========");
+            reportln!("\n=========== Phase Metrics ===========");
 
             let group_map: HashMap<String, &Metrics> = self
                 .phase_metrics
@@ -204,13 +555,111 @@ impl BenchmarkTracker {
 
             for phase_name in &self.phase_order {
                 if let Some(metrics) = group_map.get(phase_name) {
-                    print!("{}", metrics);
+                    report!("{}", metrics);
                 }
             }
-            println!("====================================\n");
+            reportln!("====================================\n");
+        }
+
+        report!("{}", total);
+    }
+
+    /// Like `print_all_metrics`, but lists the operations under the phase they ran in, followed
+    /// by the phase total. Operations outside of any phase are listed last.
+    fn print_grouped_metrics(&self) {
+        let total: Metrics = self.get_total_metrics();
+        let mut grouped: Vec<bool> = vec![false; self.completed_metrics.len()];
+
+        for phase_name in &self.phase_order {
+            reportln!("\n=========== {} ===========", phase_name);
+
+            for index in self.phase_operations.get(phase_name).into_iter().flatten() {
+                self.print_operation(&self.completed_metrics[*index]);
+                grouped[*index] = true;
+            }
+
+            for (_, metrics) in self
+                .phase_metrics
+                .iter()
+                .filter(|(name, _)| name == phase_name)
+            {
+                report!("{}", metrics);
+            }
+            reportln!("====================================\n");
+        }
+
+        for (metrics, _) in self
+            .completed_metrics
+            .iter()
+            .zip(grouped)
+            .filter(|(_, grouped)| !grouped)
+        {
+            self.print_operation(metrics);
         }
 
-        print!("{}", total);
+        report!("{}", total);
+    }
+}
+
+/// The calls made on an initialized execution context, all on its first input and output, so
+/// that the loops driving the inference can run on a stand-in in the unit tests.
+trait Inference {
+    fn set_input(
+        &mut self,
+        tensor_type: TensorType,
+        shape: &[usize],
+        data: &[u8],
+    ) -> Result<(), wasi_nn::Error>;
+    fn compute(&mut self) -> Result<(), wasi_nn::Error>;
+    /// Copies the output into `buffer`, returning its size in bytes.
+    fn get_output(&mut self, buffer: &mut [f32]) -> Result<usize, wasi_nn::Error>;
+}
+
+impl Inference for GraphExecutionContext<'_> {
+    fn set_input(
+        &mut self,
+        tensor_type: TensorType,
+        shape: &[usize],
+        data: &[u8],
+    ) -> Result<(), wasi_nn::Error> {
+        GraphExecutionContext::set_input(self, 0, tensor_type, shape, data)
+    }
+
+    fn compute(&mut self) -> Result<(), wasi_nn::Error> {
+        GraphExecutionContext::compute(self)
+    }
+
+    fn get_output(&mut self, buffer: &mut [f32]) -> Result<usize, wasi_nn::Error> {
+        GraphExecutionContext::get_output(self, 0, buffer)
+    }
+}
+
+/// A tensor ready to be passed to `set_input`.
+struct InputTensor {
+    dtype: cli::InputDtype,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+/// A tensor of the given shape with every element at the middle of the input range: 0.5 for
+/// floats and 128 for u8. It needs no file or image decoding.
+fn synthetic_tensor(dtype: cli::InputDtype, shape: Vec<usize>) -> InputTensor {
+    let elements: usize = shape.iter().product();
+    let data: Vec<u8> = match dtype {
+        cli::InputDtype::F32 => f32_vec_to_bytes(vec![0.5; elements]),
+        cli::InputDtype::F16 => f16_vec_to_bytes(vec![0.5; elements]),
+        cli::InputDtype::U8 => vec![128; elements],
+    };
+    debug_assert_eq!(data.len(), elements * dtype.byte_width());
+
+    InputTensor { dtype, shape, data }
+}
+
+impl InputTensor {
+    fn set_input(&self, context: &mut dyn Inference) -> Result<(), Box<dyn Error>> {
+        context
+            .set_input(self.dtype.tensor_type(), &self.shape, &self.data)
+            .map_err(|_| "Error occurred while setting the input".into())
     }
 }
 
@@ -221,42 +670,171 @@ fn initialize_env(model: &Graph) -> Result<GraphExecutionContext<'_>, Box<dyn Er
     }
 }
 
-fn load_model(model_path: &str) -> Result<Graph, wasi_nn::Error> {
-    GraphBuilder::new(GraphEncoding::Onnx, ExecutionTarget::CPU).build_from_files([model_path])
+/// Loads the model for `target`, in `format` or else the format its extension tells. An
+/// OpenVINO model is loaded from its `.xml` graph and the `.bin` weights next to it, whichever
+/// of the two `model_path` names.
+/// The format given by --model-format, or else by the extension of the model path.
+fn resolve_model_format(
+    model_path: &str,
+    format: Option<cli::ModelFormat>,
+) -> Result<cli::ModelFormat, Box<dyn Error>> {
+    match format {
+        Some(format) => Ok(format),
+        None => cli::ModelFormat::from_path(model_path),
+    }
 }
 
-fn read_img(image_path: &str) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, Box<dyn Error>> {
-    const IMAGE_WIDTH: u32 = 224;
-    const IMAGE_HEIGHT: u32 = 224;
+fn load_model(
+    model_path: &str,
+    target: ExecutionTarget,
+    format: Option<cli::ModelFormat>,
+) -> Result<Graph, Box<dyn Error>> {
+    let format: cli::ModelFormat = resolve_model_format(model_path, format)?;
+    let builder: GraphBuilder = GraphBuilder::new(format.graph_encoding(), target);
+    let model: Graph = match format {
+        cli::ModelFormat::OpenVino => {
+            let path: &std::path::Path = std::path::Path::new(model_path);
+            builder.build_from_files([path.with_extension("xml"), path.with_extension("bin")])?
+        }
+        _ => builder.build_from_files([model_path])?,
+    };
+    Ok(model)
+}
 
-    let image = image::imageops::resize(
-        &image::open(image_path)?,
-        IMAGE_WIDTH,
-        IMAGE_HEIGHT,
-        image::imageops::FilterType::Triangle,
-    );
+fn read_img(
+    image_path: &str,
+    preprocess: &cli::PreprocessConfig,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, Box<dyn Error>> {
+    Ok(resize_image(&image::open(image_path)?, preprocess))
+}
+
+fn resize_image(
+    image: &image::DynamicImage,
+    preprocess: &cli::PreprocessConfig,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = preprocess.size;
+    match preprocess.resize {
+        cli::ResizeMode::Stretch => {
+            image::imageops::resize(image, width, height, image::imageops::FilterType::Triangle)
+        }
+        cli::ResizeMode::ResizeShortestThenCenterCrop { resize_to } => {
+            let (image_width, image_height) = (image.width(), image.height());
+            // an image too small for the crop, or a panorama whose short side is resized below
+            // the crop height, is scaled up until the crop fits
+            let scale: f64 = (resize_to as f64 / image_width.min(image_height) as f64)
+                .max(width as f64 / image_width as f64)
+                .max(height as f64 / image_height as f64);
+            // cropping before resizing spares a panorama a huge intermediate image
+            let crop_width: u32 = ((width as f64 / scale).round() as u32).clamp(1, image_width);
+            let crop_height: u32 = ((height as f64 / scale).round() as u32).clamp(1, image_height);
+            let cropped: image::DynamicImage = image.crop_imm(
+                (image_width - crop_width) / 2,
+                (image_height - crop_height) / 2,
+                crop_width,
+                crop_height,
+            );
+            image::imageops::resize(
+                &cropped,
+                width,
+                height,
+                image::imageops::FilterType::Triangle,
+            )
+        }
+    }
+}
+
+/// Blends every pixel over an opaque background according to its alpha, and makes it opaque.
+fn composite_background(image: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, background: cli::Background) {
+    let background: f32 = background.channel_value() as f32;
+
+    for pixel in image.pixels_mut() {
+        let alpha: f32 = pixel.0[3] as f32 / 255.0;
+        for channel in pixel.0[..3].iter_mut() {
+            *channel = (*channel as f32 * alpha + background * (1.0 - alpha)).round() as u8;
+        }
+        pixel.0[3] = u8::MAX;
+    }
+}
+
+/// The pixel channel read for each of the three tensor channels. Fails unless the pixel type
+/// has red, green and blue channels, which come first in both RGB and RGBA pixels.
+fn pixel_channels<P: Pixel>(
+    channel_order: cli::ChannelOrder,
+) -> Result<[usize; 3], Box<dyn Error>> {
+    match P::COLOR_MODEL {
+        "RGB" | "RGBA" => Ok(channel_order.rgb_indices()),
+        color_model => Err(format!(
+            "Cannot read {:?} channels from {} pixels",
+            channel_order, color_model
+        )
+        .into()),
+    }
+}
 
-    Ok(image)
+pub fn image_to_tensor<P: Pixel<Subpixel = u8>>(
+    image: ImageBuffer<P, Vec<u8>>,
+    dtype: cli::InputDtype,
+    mantissa_reduction: Option<cli::MantissaReduction>,
+    preprocess: &cli::PreprocessConfig,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    image_to_tensor_into(image, dtype, mantissa_reduction, preprocess, &mut bytes)?;
+    Ok(bytes)
 }
 
-pub fn image_to_tensor(image: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>> {
-    let mut array = ndarray::Array::from_shape_fn((1, 3, 224, 224), |(_, c, j, i)| {
+/// Like `image_to_tensor`, but appends the tensor bytes to `bytes`, so that a sweep can reuse
+/// one buffer for all images.
+fn image_to_tensor_into<P: Pixel<Subpixel = u8>>(
+    image: ImageBuffer<P, Vec<u8>>,
+    dtype: cli::InputDtype,
+    mantissa_reduction: Option<cli::MantissaReduction>,
+    preprocess: &cli::PreprocessConfig,
+    bytes: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = image.dimensions();
+    let shape = (1, 3, height as usize, width as usize);
+    let pixel_channels: [usize; 3] = pixel_channels::<P>(preprocess.channel_order)?;
+
+    if dtype == cli::InputDtype::U8 {
+        // u8 models take the raw pixel values without normalization
+        let array = ndarray::Array::from_shape_fn(shape, |(_, c, j, i)| {
+            image.get_pixel(i as u32, j as u32).channels()[pixel_channels[c]]
+        });
+        bytes.extend_from_slice(array.as_slice().unwrap());
+        return Ok(());
+    }
+
+    let mut array = ndarray::Array::from_shape_fn(shape, |(_, c, j, i)| {
         let pixel = image.get_pixel(i as u32, j as u32);
         let channels = pixel.channels();
 
         // range [0, 255] -> range [0, 1]
-        (channels[c] as f32) / 255.0
+        (channels[pixel_channels[c]] as f32) / 255.0
     });
 
-    let mean = [0.485, 0.456, 0.406];
-    let std = [0.229, 0.224, 0.225];
-    for c in 0..3 {
+    // the mean and std are in RGB order
+    for (c, &channel) in pixel_channels.iter().enumerate() {
         let mut channel_array = array.slice_mut(s![0, c, .., ..]);
-        channel_array -= mean[c];
-        channel_array /= std[c];
+        channel_array -= preprocess.mean[channel];
+        channel_array /= preprocess.std[channel];
+    }
+
+    let values: &[f32] = array.as_slice().unwrap();
+    let start: usize = bytes.len();
+    bytes.reserve(values.len() * dtype.byte_width());
+    for value in values {
+        let value: f32 = match mantissa_reduction {
+            Some(mantissa_reduction) => mantissa_reduction.apply(*value),
+            None => *value,
+        };
+        match dtype {
+            cli::InputDtype::F16 => bytes.extend(half::f16::from_f32(value).to_le_bytes()),
+            _ => bytes.extend(value.to_le_bytes()),
+        }
     }
+    debug_assert_eq!(bytes.len() - start, values.len() * dtype.byte_width());
 
-    Ok(f32_vec_to_bytes(array.as_slice().unwrap().to_vec()))
+    Ok(())
 }
 
 fn f32_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
@@ -271,102 +849,2898 @@ fn f32_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
     result
 }
 
-fn process_image(image_path: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>> {
-    image_to_tensor(image_path)
+fn f16_vec_to_bytes(data: Vec<f32>) -> Vec<u8> {
+    data.into_iter()
+        .flat_map(|f| half::f16::from_f32(f).to_le_bytes())
+        .collect()
+}
+
+fn process_image(
+    image_path: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &cli::Options,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    process_image_into(image_path, options, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn process_image_into(
+    mut image_path: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    options: &cli::Options,
+    bytes: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(background) = options.background {
+        composite_background(&mut image_path, background);
+    }
+    image_to_tensor_into(
+        image_path,
+        options.input_dtype,
+        options.mantissa_reduction,
+        &options.preprocess,
+        bytes,
+    )
+}
+
+/// The input and output buffers of the pipeline, kept across the items of a sweep.
+#[derive(Default)]
+struct PipelineBuffers {
+    input: Vec<u8>,
+    output: Vec<f32>,
+}
+
+impl PipelineBuffers {
+    /// Empties the buffers for the next item, keeping their capacity with `--reuse-buffers`
+    /// and dropping it otherwise, so that every item allocates its buffers again.
+    fn reset(&mut self, reuse: bool) {
+        if reuse {
+            self.input.clear();
+            self.output.clear();
+        } else {
+            *self = PipelineBuffers::default();
+        }
+    }
 }
 
-fn run_model(context: &mut GraphExecutionContext) -> Result<(), Box<dyn Error>> {
+fn run_model(context: &mut dyn Inference) -> Result<(), Box<dyn Error>> {
     context.compute().map_err(|_| {
         Box::<dyn std::error::Error>::from("Error occurred while running the model")
     })?;
     Ok(())
 }
 
-fn post_process(
-    context: &mut GraphExecutionContext,
-    image_name: &str,
-) -> Result<i32, Box<dyn Error>> {
-    const OUTPUT_BUFFER_CAPACITY: usize = 4000;
-    let mut output_buffer: Vec<f32> = vec![0.0; OUTPUT_BUFFER_CAPACITY];
-    let context = context;
+/// Runs the model, retrying a failed compute up to `retries` times with a backoff that doubles
+/// after every attempt. The backoff is left out of the timings and the number of retries is
+/// recorded as the `retries` tag of the operation in progress.
+fn run_model_with_retries(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    retries: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut backoff: Duration = RETRY_BACKOFF;
+    let mut attempt: usize = 0;
+    loop {
+        match run_model(context) {
+            Ok(()) => break,
+            Err(_) if attempt < retries => {
+                attempt += 1;
+                tracker.sleep_untracked(backoff);
+                backoff *= 2;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    if attempt > 0 {
+        tracker.tag_operation("retries", attempt.to_string());
+    }
+    Ok(())
+}
 
-    match context.get_output(0, &mut output_buffer) {
-        Ok(_) => (),
-        Err(_) => return Err("Error occurred while getting output".into()),
+/// Runs the model until `options.warmup` has passed and `options.warmup_iterations` runs are
+/// done, at least once, as a "Warmup" operation tagged with the number of runs, so that fast
+/// and slow models both get warm before the timed iterations.
+fn warm_up(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    options: &cli::Options,
+) -> Result<(), Box<dyn Error>> {
+    let mut operation = tracker.operation("Warmup");
+    let start: Instant = Instant::now();
+    let mut iterations: usize = 0;
+    while iterations == 0
+        || iterations < options.warmup_iterations
+        || start.elapsed() < options.warmup
+    {
+        run_model_with_retries(&mut operation, context, options.retries)?;
+        iterations += 1;
     }
+    operation.tag_operation("iterations", iterations.to_string());
+    Ok(())
+}
 
-    let result = output_buffer
-        .iter()
-        .cloned()
-        .zip(RangeFrom::<i32> { start: 1 })
-        .max_by(|(score1, _), (score2, _)| score1.partial_cmp(score2).unwrap_or(Ordering::Equal))
-        .map_or_else(|| Err("testing"), Ok);
+/// Runs the inference at batch sizes 1, 2, 4, ... up to `max_batch_size`, with every batch
+/// made of copies of the single item `input`, and returns the images per second reached at
+/// each batch size.
+fn batch_sweep(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    input: &InputTensor,
+    options: &cli::Options,
+    max_batch_size: usize,
+) -> Result<Vec<(usize, f64)>, Box<dyn Error>> {
+    let batch_sizes = std::iter::successors(Some(1), |size: &usize| Some(size * 2))
+        .take_while(|size| *size <= max_batch_size);
+
+    let mut throughput: Vec<(usize, f64)> = Vec::new();
+    for batch_size in batch_sizes {
+        if tracker.over_budget() {
+            break;
+        }
+        let batch: Vec<u8> = input.data.repeat(batch_size);
+        // the first dimension of the input is the batch
+        let shape: Vec<usize> = std::iter::once(batch_size)
+            .chain(input.shape[1..].iter().cloned())
+            .collect();
+        context
+            .set_input(input.dtype.tensor_type(), &shape, &batch)
+            .map_err(|_| "Error occurred while setting the batched input")?;
+
+        let name: String = format!("Inference (batch {})", batch_size);
+        let tags: Vec<(String, String)> = options
+            .tags
+            .iter()
+            .cloned()
+            .chain([("batch_size".to_string(), batch_size.to_string())])
+            .collect();
+        for iteration in 0..options.iterations {
+            if iteration > 0 {
+                if tracker.over_budget() {
+                    break;
+                }
+                tracker.sleep_untracked(options.cooldown);
+            }
+            tracker.start_tagged_operation(&name, &tags);
+            run_model_with_retries(tracker, context, options.retries)?;
+            tracker.finish_operation();
+        }
+
+        let samples: Vec<&Metrics> = tracker.operation_samples(&name);
+        let wall_clock_time: Duration = samples.iter().map(|metrics| metrics.wall_clock_time).sum();
+        let images: usize = batch_size * samples.len();
+        throughput.push((batch_size, images as f64 / wall_clock_time.as_secs_f64()));
+    }
+
+    Ok(throughput)
+}
+
+/// The prediction for one image of an image sweep.
+struct SweepItem {
+    path: String,
+    class: i32,
+    score: f32,
+    inference_time: Duration,
+}
+
+/// Predictions and throughput of an image sweep.
+struct ImageSweep {
+    /// Every image, in path order.
+    items: Vec<SweepItem>,
+    /// Images per second, from reading each image to its inference.
+    images_per_second: f64,
+}
+
+impl ImageSweep {
+    fn inference_times(&self) -> Vec<Duration> {
+        self.items.iter().map(|item| item.inference_time).collect()
+    }
+
+    /// Correct and labeled images according to the ground truth, which is keyed by file name.
+    /// `None` if no image has a label.
+    fn accuracy(&self, ground_truth: &HashMap<String, i32>) -> Option<(usize, usize)> {
+        let labeled: Vec<bool> = self
+            .items
+            .iter()
+            .filter_map(|item| {
+                let file_name = std::path::Path::new(&item.path).file_name()?.to_str()?;
+                ground_truth
+                    .get(file_name)
+                    .map(|label| *label == item.class)
+            })
+            .collect();
+        let correct: usize = labeled.iter().filter(|correct| **correct).count();
+
+        (!labeled.is_empty()).then_some((correct, labeled.len()))
+    }
+}
 
-    match result {
-        Ok((score, class)) => {
-            println!("{}: {} (score: {})", image_name, class, score);
-            Ok(class)
+/// The images of a directory, read `chunk_size` entries at a time and sorted within each chunk,
+/// so that at most one chunk of paths is held at once. With a chunk larger than the directory,
+/// every image is listed in path order.
+struct ImagePaths {
+    entries: fs::ReadDir,
+    /// The sorted paths of the current chunk, in reverse to pop the next one off the end.
+    chunk: Vec<std::path::PathBuf>,
+    chunk_size: usize,
+}
+
+impl Iterator for ImagePaths {
+    type Item = std::io::Result<std::path::PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.chunk.is_empty() {
+            let mut read: usize = 0;
+            for entry in self.entries.by_ref().take(self.chunk_size) {
+                read += 1;
+                match entry {
+                    Ok(entry) if image::ImageFormat::from_path(entry.path()).is_ok() => {
+                        self.chunk.push(entry.path())
+                    }
+                    Ok(_) => {}
+                    Err(error) => return Some(Err(error)),
+                }
+            }
+            // a chunk can hold no images, so only an exhausted directory ends the paths
+            if read == 0 {
+                return None;
+            }
+            self.chunk.sort_by(|a, b| b.cmp(a));
         }
-        Err(error) => {
-            println!("Error: {:?}", error);
-            Err("Error: ".into())
+        self.chunk.pop().map(Ok)
+    }
+}
+
+/// Runs every image in `dir` through the already loaded model and initialized context, so
+/// that only reading, preprocessing and inference are repeated per image.
+fn image_sweep(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    dir: &str,
+    options: &cli::Options,
+) -> Result<ImageSweep, Box<dyn Error>> {
+    let paths = ImagePaths {
+        entries: fs::read_dir(dir)?,
+        chunk: Vec::new(),
+        chunk_size: options.sweep_chunk.unwrap_or(usize::MAX),
+    };
+
+    let mut items: Vec<SweepItem> = Vec::new();
+    let mut wall_clock_time: Duration = Duration::default();
+    let mut buffers: PipelineBuffers = PipelineBuffers::default();
+    for path in paths {
+        if tracker.over_budget() {
+            break;
         }
+        let path: std::path::PathBuf = path?;
+        buffers.reset(options.reuse_buffers);
+        let path: String = path.to_string_lossy().into_owned();
+        let tags: [(String, String); 1] = [("image".to_string(), path.clone())];
+        let start_index: usize = tracker.completed_metrics.len();
+
+        tracker.start_tagged_operation("readimg (sweep)", &tags);
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> = read_img(&path, &options.preprocess)?;
+        tracker.finish_operation();
+
+        tracker.start_tagged_operation("Pre-processing (sweep)", &tags);
+        let shape: Vec<usize> = options.preprocess.input_shape();
+        process_image_into(image, options, &mut buffers.input)?;
+        context
+            .set_input(options.input_dtype.tensor_type(), &shape, &buffers.input)
+            .map_err(|_| "Error occurred while setting the input")?;
+        tracker.finish_operation();
+
+        tracker.start_tagged_operation("Inference (sweep)", &tags);
+        run_model_with_retries(tracker, context, options.retries)?;
+        tracker.finish_operation();
+        let inference_time: Duration = tracker.completed_metrics.last().unwrap().wall_clock_time;
+
+        wall_clock_time += tracker.completed_metrics[start_index..]
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .sum::<Duration>();
+        let (class, score) = top_prediction(context, options, &mut buffers.output)?;
+        items.push(SweepItem {
+            path,
+            class,
+            score,
+            inference_time,
+        });
     }
+
+    let images_per_second: f64 = if wall_clock_time.is_zero() {
+        0.0
+    } else {
+        items.len() as f64 / wall_clock_time.as_secs_f64()
+    };
+    Ok(ImageSweep {
+        items,
+        images_per_second,
+    })
 }
 
-#[no_mangle]
-pub fn main() {
-    // let args: Vec<String> = env::args().collect();
+/// Decode time of the benchmark image in one format.
+struct FormatDecode {
+    format: image::ImageFormat,
+    /// Size of the encoded image.
+    bytes: usize,
+    median_decode: Option<Duration>,
+}
 
-    // if args.len() != 3 {
-    //     return Err(format!("Usage: {} <model> <image>", args[0]).into());
-    // }
+/// Re-encodes the image at `image_path` as JPEG, PNG and lossless WebP in memory and decodes
+/// each encoding `options.iterations` times, so that the formats are compared on the same
+/// content without any file reads in the timings.
+fn decode_formats(
+    tracker: &mut BenchmarkTracker,
+    image_path: &str,
+    options: &cli::Options,
+) -> Result<Vec<FormatDecode>, Box<dyn Error>> {
+    // JPEG has no alpha channel, so all formats get the RGB pixels
+    let image: image::DynamicImage = image::open(image_path)?.to_rgb8().into();
+
+    let mut decodes: Vec<FormatDecode> = Vec::new();
+    for format in [
+        image::ImageFormat::Jpeg,
+        image::ImageFormat::Png,
+        image::ImageFormat::WebP,
+    ] {
+        let mut encoded: std::io::Cursor<Vec<u8>> = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format)?;
+        let encoded: Vec<u8> = encoded.into_inner();
+
+        let name: String = format!("decode ({:?})", format);
+        for _ in 0..options.iterations {
+            let _operation = tracker.operation(&name);
+            image::load_from_memory_with_format(&encoded, format)?;
+        }
+        let decode_times: Vec<Duration> = tracker
+            .operation_samples(&name)
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+
+        decodes.push(FormatDecode {
+            format,
+            bytes: encoded.len(),
+            median_decode: stats::median(&decode_times),
+        });
+    }
+    Ok(decodes)
+}
 
-    let model_path: String = String::from("/assets/models/mobilenetv2-10.onnx");
-    let image_path: String = String::from("/assets/imgs/unseen_dog.jpg");
+/// Reads length-prefixed frames from `path`, usually a named pipe fed by a camera or a script,
+/// and decodes, preprocesses and infers each as a "Frame (stream)" operation, until the writer
+/// closes the pipe. Every frame is a `u32` little-endian length followed by an encoded image.
+/// Waiting for the next frame is left out of the frame latency, and a rolling mean of the
+/// latency is printed as the frames come in. Returns the frames per second over the stream.
+fn frame_stream(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    path: &str,
+    options: &cli::Options,
+) -> Result<f64, Box<dyn Error>> {
+    // opening a named pipe blocks until the writer opens it as well
+    let mut pipe: fs::File = fs::File::open(path)?;
+    stream_frames(tracker, context, &mut pipe, path, options)
+}
 
-    let mut tracker: BenchmarkTracker = BenchmarkTracker::new();
+/// `frame_stream` on the frames of `reader`, which `source` names in the errors.
+fn stream_frames(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    reader: &mut dyn Read,
+    source: &str,
+    options: &cli::Options,
+) -> Result<f64, Box<dyn Error>> {
+    const ROLLING_WINDOW: usize = 10;
+    const FRAME_STREAM: &str = "Frame (stream)";
+
+    let mut buffers: PipelineBuffers = PipelineBuffers::default();
+    let mut first_start: Option<Instant> = None;
+    let mut frame: usize = 0;
+    loop {
+        if tracker.over_budget() {
+            break;
+        }
+        let Some(encoded) = read_frame(reader, source, frame)? else {
+            break;
+        };
 
-    // RED BOX: Environment setup, image loading, processing, and model loading
-    tracker.start_phase("RED BOX Phase");
+        buffers.reset(options.reuse_buffers);
+        {
+            let mut operation = tracker.operation(FRAME_STREAM);
+            operation.tag_operation("frame", frame.to_string());
+            first_start.get_or_insert_with(Instant::now);
+            let image = resize_image(&image::load_from_memory(&encoded)?, &options.preprocess);
+            let shape: Vec<usize> = options.preprocess.input_shape();
+            process_image_into(image, options, &mut buffers.input)?;
+            context
+                .set_input(options.input_dtype.tensor_type(), &shape, &buffers.input)
+                .map_err(|_| "Error occurred while setting the input")?;
+            run_model_with_retries(&mut operation, context, options.retries)?;
+        }
 
-    tracker.start_operation("loadmodel");
-    let model: Result<Graph, wasi_nn::Error> = load_model(model_path.as_str());
-    let model = model.unwrap();
-    tracker.finish_operation();
+        let latencies: Vec<Duration> = tracker
+            .operation_samples(FRAME_STREAM)
+            .iter()
+            .rev()
+            .take(ROLLING_WINDOW)
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+        reportln!(
+            "Frame {}: latency {:?}, rolling mean {:?}",
+            frame,
+            latencies[0],
+            stats::mean(&latencies).unwrap_or_default()
+        );
+        frame += 1;
+    }
 
-    tracker.start_operation("envload");
-    let mut context: GraphExecutionContext<'_> = initialize_env(&model).unwrap();
-    tracker.finish_operation();
+    Ok(match first_start {
+        Some(start) if frame > 0 => frame as f64 / start.elapsed().as_secs_f64(),
+        _ => 0.0,
+    })
+}
 
-    tracker.start_operation("readimg");
-    let original_img: ImageBuffer<Rgba<u8>, Vec<u8>> = read_img(image_path.as_str()).unwrap();
-    tracker.finish_operation();
+/// Reads the next length-prefixed frame, `None` once the writer has closed the stream.
+fn read_frame(
+    reader: &mut dyn Read,
+    source: &str,
+    frame: usize,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    // even an uncompressed 4K RGBA frame is smaller, so a larger length is a corrupt header
+    const MAX_FRAME_BYTES: usize = 64 << 20;
+
+    let mut len: [u8; 4] = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let frame_bytes: usize = u32::from_le_bytes(len) as usize;
+    if frame_bytes > MAX_FRAME_BYTES {
+        return Err(format!(
+            "{}: frame {} claims {} bytes, more than the {} bytes a frame can have",
+            source, frame, frame_bytes, MAX_FRAME_BYTES
+        )
+        .into());
+    }
+    let mut encoded: Vec<u8> = vec![0; frame_bytes];
+    reader
+        .read_exact(&mut encoded)
+        .map_err(|error| format!("{}: frame {} is truncated: {}", source, frame, error))?;
+    Ok(Some(encoded))
+}
 
-    tracker.end_phase("RED BOX Phase");
+/// Reads the image, if there is one, and every image of `image_dir` once and drops the
+/// contents, so that the timed reads find them in the page cache. The reads are a "Warm page
+/// cache" operation of their own. Returns the number of files and bytes read.
+fn warm_page_cache(
+    tracker: &mut BenchmarkTracker,
+    image_path: Option<&str>,
+    image_dir: Option<&str>,
+) -> Result<(usize, u64), Box<dyn Error>> {
+    let _operation = tracker.operation("Warm page cache");
+    let mut paths: Vec<std::path::PathBuf> = image_path.into_iter().map(Into::into).collect();
+    if let Some(dir) = image_dir {
+        for entry in fs::read_dir(dir)? {
+            let path: std::path::PathBuf = entry?.path();
+            if image::ImageFormat::from_path(&path).is_ok() {
+                paths.push(path);
+            }
+        }
+    }
 
-    // GREEN BOX: Model inference and post-processing
-    tracker.start_phase("GREEN BOX Phase");
+    let mut bytes: u64 = 0;
+    for path in &paths {
+        bytes += std::io::copy(&mut fs::File::open(path)?, &mut std::io::sink())?;
+    }
+    Ok((paths.len(), bytes))
+}
 
-    tracker.start_operation("Pre-processing");
-    let input = process_image(original_img).unwrap();
-    context.set_input(0, wasi_nn::TensorType::F32, &[1, 3, 224, 224], &input);
-    tracker.finish_operation();
+/// Runs every tensor of a `.npy` tensor file through the model. The first dimension of the
+/// array indexes the tensors, so a file of shape `(100, 1, 3, 224, 224)` holds 100 inputs of
+/// shape `(1, 3, 224, 224)`. Nothing is decoded per item, so only `set_input` and the inference
+/// are measured. Returns the inputs per second.
+fn tensor_file_sweep(
+    tracker: &mut BenchmarkTracker,
+    context: &mut dyn Inference,
+    path: &str,
+    options: &cli::Options,
+) -> Result<f64, Box<dyn Error>> {
+    let array: npy::NpyArray = {
+        let _operation = tracker.operation("readnpy (tensor file)");
+        npy::read_npy(path)?
+    };
+    let Some((&count, shape)) = array.shape.split_first() else {
+        return Err(format!("{}: a tensor file needs at least one dimension", path).into());
+    };
+    let tensor_len: usize = shape.iter().product::<usize>() * array.dtype.byte_width();
+    if tensor_len == 0 {
+        return Err(format!("{}: the tensors in the file are empty", path).into());
+    }
 
-    tracker.start_operation("Inference");
-    let _ = run_model(&mut context);
-    tracker.finish_operation();
+    let start_index: usize = tracker.completed_metrics.len();
+    let mut completed: usize = 0;
+    for (index, tensor) in array.data.chunks_exact(tensor_len).take(count).enumerate() {
+        if tracker.over_budget() {
+            break;
+        }
+        let tags: [(String, String); 1] = [("tensor".to_string(), index.to_string())];
+
+        tracker.start_tagged_operation("set_input (tensor file)", &tags);
+        context
+            .set_input(array.dtype.tensor_type(), shape, tensor)
+            .map_err(|_| "Error occurred while setting the input")?;
+        tracker.finish_operation();
+
+        tracker.start_tagged_operation("Inference (tensor file)", &tags);
+        run_model_with_retries(tracker, context, options.retries)?;
+        tracker.finish_operation();
+        completed += 1;
+    }
 
-    tracker.start_operation("Post-processing");
-    let output: i32 = post_process(&mut context, image_path.as_str()).unwrap();
-    tracker.finish_operation();
+    let wall_clock_time: Duration = tracker.completed_metrics[start_index..]
+        .iter()
+        .map(|metrics| metrics.wall_clock_time)
+        .sum();
+    Ok(if wall_clock_time.is_zero() {
+        0.0
+    } else {
+        completed as f64 / wall_clock_time.as_secs_f64()
+    })
+}
 
-    tracker.end_phase("GREEN BOX Phase");
+/// Accuracy and speed of one model in a model comparison.
+struct ModelComparison {
+    model: String,
+    /// Correct and labeled images, if any image had a label.
+    accuracy: Option<(usize, usize)>,
+    median_inference: Option<Duration>,
+}
 
-    tracker.print_all_metrics();
+/// Loads each model in turn and sweeps the images in `dir` through it, then scores the
+/// predictions against the ground truth labels.
+fn compare_models(
+    models: &[String],
+    dir: &str,
+    ground_truth: &HashMap<String, i32>,
+    options: &cli::Options,
+) -> Result<Vec<ModelComparison>, Box<dyn Error>> {
+    let mut comparisons: Vec<ModelComparison> = Vec::new();
+    for model_path in models {
+        let model: Graph = load_model(
+            model_path,
+            options.target.execution_target(),
+            options.model_format,
+        )
+        .map_err(|error| format!("Error occurred while loading {}: {}", model_path, error))?;
+        let mut context: GraphExecutionContext<'_> = initialize_env(&model)?;
+        comparisons.push(score_model(
+            model_path,
+            &mut context,
+            dir,
+            ground_truth,
+            options,
+        )?);
+    }
 
-    println!("Predicted Class Index: {}", output);
+    Ok(comparisons)
+}
 
-    // let number_threads: NonZero<usize> = num_threads().unwrap();
-    // println!("Number of Threads: {:?}", number_threads);
+/// Sweeps the images in `dir` through one model of a model comparison and scores it.
+fn score_model(
+    model_path: &str,
+    context: &mut dyn Inference,
+    dir: &str,
+    ground_truth: &HashMap<String, i32>,
+    options: &cli::Options,
+) -> Result<ModelComparison, Box<dyn Error>> {
+    // each model gets its own tracker so that the samples of the models stay apart
+    let mut tracker: BenchmarkTracker = BenchmarkTracker::new();
+    let sweep: ImageSweep = image_sweep(&mut tracker, context, dir, options)?;
+
+    Ok(ModelComparison {
+        model: model_path.to_string(),
+        accuracy: sweep.accuracy(ground_truth),
+        median_inference: stats::median(&sweep.inference_times()),
+    })
+}
+
+/// The image sweep at one input size of a size sweep.
+struct SizeComparison {
+    size: (u32, u32),
+    /// The sweep, or why it failed, e.g. because the model has a fixed input size.
+    sweep: Result<ImageSweep, String>,
+}
+
+/// Sweeps the images in `dir` through the already loaded model at each square input size, each
+/// size with its own tracker. A size the model rejects is reported instead of ending the sweep.
+fn size_sweep(
+    context: &mut dyn Inference,
+    sizes: &[u32],
+    dir: &str,
+    options: &cli::Options,
+) -> Vec<SizeComparison> {
+    sizes
+        .iter()
+        .map(|size| {
+            let options = cli::Options {
+                preprocess: cli::PreprocessConfig {
+                    size: (*size, *size),
+                    ..options.preprocess
+                },
+                ..options.clone()
+            };
+            let mut tracker: BenchmarkTracker = BenchmarkTracker::new();
+            SizeComparison {
+                size: options.preprocess.size,
+                sweep: image_sweep(&mut tracker, context, dir, &options)
+                    .map_err(|error| error.to_string()),
+            }
+        })
+        .collect()
+}
+
+/// Formats the accuracy as a percentage with the counts, or "n/a" without labeled images.
+fn accuracy_text(accuracy: Option<(usize, usize)>) -> String {
+    match accuracy {
+        Some((correct, labeled)) => format!(
+            "{:.1}% ({}/{})",
+            correct as f64 / labeled as f64 * 100.0,
+            correct,
+            labeled
+        ),
+        None => "n/a".to_string(),
+    }
+}
+
+/// Median inference time on one execution target, or why the target couldn't be measured.
+struct TargetComparison {
+    target: cli::Target,
+    median_inference: Result<Duration, String>,
+}
+
+/// Loads the model for each target in turn and runs the inference `options.iterations` times
+/// on `input`. A target that fails, e.g. a GPU the backend can't use, is reported instead of
+/// ending the comparison. The ONNX backend of `wasmtime-custom` only loads models for the CPU.
+fn compare_targets(
+    targets: &[cli::Target],
+    model_path: &str,
+    input: &InputTensor,
+    options: &cli::Options,
+) -> Vec<TargetComparison> {
+    let measure = |target: cli::Target| -> Result<Duration, Box<dyn Error>> {
+        let model: Graph = load_model(model_path, target.execution_target(), options.model_format)
+            .map_err(|error| {
+                format!(
+                    "Error occurred while loading {} for {:?}: {}",
+                    model_path, target, error
+                )
+            })?;
+        let mut context: GraphExecutionContext<'_> = initialize_env(&model)?;
+        input.set_input(&mut context)?;
+
+        let mut tracker: BenchmarkTracker = BenchmarkTracker::new();
+        for iteration in 0..options.iterations {
+            if iteration > 0 {
+                tracker.sleep_untracked(options.cooldown);
+            }
+            tracker.start_tagged_operation("Inference", &options.tags);
+            run_model_with_retries(&mut tracker, &mut context, options.retries)?;
+            tracker.finish_operation();
+        }
+        let inference_times: Vec<Duration> = tracker
+            .operation_samples("Inference")
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+        Ok(stats::median(&inference_times).unwrap_or_default())
+    };
+
+    targets
+        .iter()
+        .map(|target| TargetComparison {
+            target: *target,
+            median_inference: measure(*target).map_err(|error| error.to_string()),
+        })
+        .collect()
+}
+
+/// Prints a warning, or fails with it under `--strict`.
+fn warn(options: &cli::Options, message: &str) -> Result<(), Box<dyn Error>> {
+    if options.strict {
+        return Err(message.into());
+    }
+    reportln!("Warning: {}", message);
+    Ok(())
+}
+
+/// Like `warn` for `main`, which ends the run with `STRICT_EXIT_CODE` under `--strict` rather
+/// than panicking.
+fn warn_or_exit(options: &cli::Options, message: &str) {
+    if let Err(error) = warn(options, message) {
+        reportln!("Error: {}", error);
+        std::process::exit(STRICT_EXIT_CODE);
+    }
+}
+
+/// The preprocessing time of the input, the mean time of one inference and the ratio of the
+/// two, or `None` without any inference. The image is prepared once but inferred every
+/// iteration, so the time of one input is compared with the mean time of one inference.
+fn bottleneck(tracker: &BenchmarkTracker) -> Option<(Duration, Duration, f64)> {
+    const INFERENCES: [&str; 2] = ["Inference", FIRST_INFERENCE];
+    let preprocessing: Duration =
+        tracker.operations_time(&["readimg", "readnpy", "Pre-processing"]);
+    let inference_runs: usize = INFERENCES
+        .iter()
+        .map(|name| tracker.operation_samples(name).len())
+        .sum();
+    let inference: Duration = match inference_runs {
+        0 => return None,
+        runs => tracker.operations_time(&INFERENCES) / runs as u32,
+    };
+    if inference.is_zero() {
+        return None;
+    }
+    Some((
+        preprocessing,
+        inference,
+        preprocessing.as_secs_f64() / inference.as_secs_f64(),
+    ))
+}
+
+/// Whether the data path or the model bounds a run with the given `bottleneck` ratio.
+fn bottleneck_kind(ratio: f64) -> &'static str {
+    if ratio > 1.0 {
+        "preprocessing-bound"
+    } else {
+        "compute-bound"
+    }
+}
+
+/// The value of a step of `main` that the run can't go on without, or the end of the run with
+/// exit code 1 and the error.
+fn exit_on_error<T>(result: Result<T, Box<dyn Error>>) -> T {
+    result.unwrap_or_else(|error| {
+        reportln!("Error: {}", error);
+        std::process::exit(1);
+    })
+}
+
+/// Reads the first output of the model.
+fn read_output(context: &mut dyn Inference) -> Result<Vec<f32>, Box<dyn Error>> {
+    let mut output_buffer: Vec<f32> = Vec::new();
+    read_output_into(context, &mut output_buffer)?;
+    Ok(output_buffer)
+}
+
+/// Reads the first output of the model into `output_buffer`, replacing its contents.
+fn read_output_into(
+    context: &mut dyn Inference,
+    output_buffer: &mut Vec<f32>,
+) -> Result<(), Box<dyn Error>> {
+    // wasi-nn doesn't report the output size, so a buffer the backend reports as too small is
+    // grown and the read retried, up to 64 MiB of f32s
+    const INITIAL_OUTPUT_CAPACITY: usize = 4000;
+    const MAX_OUTPUT_CAPACITY: usize = 1 << 24;
+
+    let mut capacity: usize = output_buffer.capacity().max(INITIAL_OUTPUT_CAPACITY);
+    loop {
+        output_buffer.clear();
+        output_buffer.resize(capacity, 0.0);
+        match context.get_output(output_buffer) {
+            Ok(output_bytes) => {
+                // only the written elements are the output
+                output_buffer.truncate(output_bytes / std::mem::size_of::<f32>());
+                return Ok(());
+            }
+            Err(error) if is_too_large(&error) && capacity < MAX_OUTPUT_CAPACITY => capacity *= 2,
+            Err(error) => {
+                return Err(format!("Error occurred while getting output: {}", error).into())
+            }
+        }
+    }
+}
+
+/// Whether a wasi-nn call failed because the guest buffer was too small.
+///
+/// The wasi-nn crate doesn't export its backend error type, so the errno is matched by its
+/// `Debug` name.
+fn is_too_large(error: &wasi_nn::Error) -> bool {
+    matches!(error, wasi_nn::Error::BackendError(errno) if format!("{:?}", errno) == "TooLarge")
+}
+
+/// The class and score predicted by the last inference, without printing anything. The output
+/// is read into `output_buffer`.
+fn top_prediction(
+    context: &mut dyn Inference,
+    options: &cli::Options,
+    output_buffer: &mut Vec<f32>,
+) -> Result<(i32, f32), Box<dyn Error>> {
+    read_output_into(context, output_buffer)?;
+    let logits: &[f32] =
+        postprocess::squeeze_logits(output_buffer, options.output_shape.as_deref())?;
+    predicted_class(logits, options).ok_or_else(|| "The model output is empty".into())
+}
+
+/// The class and score of the highest logit, breaking near-ties with `--tie-tolerance`.
+fn predicted_class(logits: &[f32], options: &cli::Options) -> Option<(i32, f32)> {
+    match options.tie_tolerance {
+        Some(tolerance) => postprocess::argmax_with_tolerance(logits, tolerance),
+        None => postprocess::argmax(logits),
+    }
+}
+
+/// The label of a predicted class, if there is one.
+fn class_label(labels: &HashMap<usize, String>, class: i32) -> Option<&str> {
+    labels.get(&(class as usize)).map(String::as_str)
+}
+
+/// Writes an annotated copy of each predicted image to `dir`, after the timed work so that the
+/// encoding doesn't count.
+fn write_annotated_images(
+    predictions: &[(&str, i32)],
+    labels: &HashMap<usize, String>,
+    dir: &str,
+) -> Result<(), Box<dyn Error>> {
+    for (image_path, class) in predictions {
+        export::write_annotated(image_path, *class, class_label(labels, *class), dir)?;
+    }
+    Ok(())
+}
+
+fn post_process(
+    context: &mut dyn Inference,
+    image_name: &str,
+    labels: &HashMap<usize, String>,
+    options: &cli::Options,
+) -> Result<Option<i32>, Box<dyn Error>> {
+    let output: Vec<f32> = read_output(context)?;
+    let output: &[f32] = &output;
+    let logits: &[f32] = postprocess::squeeze_logits(output, options.output_shape.as_deref())?;
+
+    if logits.iter().any(|logit| !logit.is_finite()) {
+        warn(options, "the output contains NaN or infinite values")?;
+    }
+    if options.validation == cli::Validation::Softmax
+        && !postprocess::print_softmax_diagnostics(logits)
+    {
+        warn(
+            options,
+            "softmax sum is not 1.0, the output may be decoded incorrectly",
+        )?;
+    }
+
+    if let Some(reference_path) = &options.reference_npy {
+        let reference: Vec<f32> = npy::read_npy(reference_path)?.to_f32();
+        let comparison = postprocess::compare_output(output, &reference)?;
+        let passed: bool = comparison.max_abs_error <= options.tolerance;
+        reportln!(
+            "Reference check: max abs error {}, max rel error {} (tolerance {}): {}",
+            comparison.max_abs_error,
+            comparison.max_rel_error,
+            options.tolerance,
+            if passed { "PASS" } else { "FAIL" }
+        );
+        if !passed {
+            return Err("The output differs from the reference".into());
+        }
+    }
+
+    let (class, score) = predicted_class(logits, options).ok_or("The model output is empty")?;
+
+    let probability: Option<f32> = options
+        .threshold
+        .map(|_| postprocess::top_probability(logits));
+    let class: Option<i32> = match (probability, options.threshold) {
+        (Some(probability), Some(threshold)) if probability < threshold => None,
+        _ => Some(class),
+    };
+    let label: Option<&str> = class.and_then(|class| class_label(labels, class));
+
+    if options.prediction_json {
+        reportln!(
+            "{}",
+            export::prediction_json(image_name, class, label, score)
+        );
+    } else if let (Some(class), Some(label)) = (class, label) {
+        reportln!("{}: {} {} (score: {})", image_name, class, label, score);
+    } else if let Some(class) = class {
+        reportln!("{}: {} (score: {})", image_name, class, score);
+    } else {
+        reportln!(
+            "{}: unknown (top probability {} is below the threshold {})",
+            image_name,
+            probability.unwrap_or_default(),
+            options.threshold.unwrap_or_default()
+        );
+    }
+
+    if !options.prediction_json {
+        reportln!("Top-{}:", options.top_k);
+        for (rank, (class, probability)) in postprocess::top_k(logits, options.top_k)
+            .into_iter()
+            .enumerate()
+        {
+            match class_label(labels, class) {
+                Some(label) => reportln!("  {}. {} {}: {:.4}", rank + 1, class, label, probability),
+                None => reportln!("  {}. {}: {:.4}", rank + 1, class, probability),
+            }
+        }
+    }
+    Ok(class)
+}
+
+/// Prints the number of samples in a binary samples file with their wall-clock statistics, mean
+/// CPU times and peak RSS.
+fn summarize_samples(path: &str) -> Result<(), Box<dyn Error>> {
+    let (run_id, samples) = samples::read(path)?;
+    let wall_clock_times: Vec<Duration> = samples
+        .iter()
+        .map(|sample| sample.wall_clock_time)
+        .collect();
+    let user_times: Vec<Duration> = samples.iter().map(|sample| sample.user_time).collect();
+    let system_times: Vec<Duration> = samples.iter().map(|sample| sample.system_time).collect();
+
+    reportln!("============= Samples Summary =============");
+    if let Some(run_id) = run_id {
+        reportln!("Run ID: {}", run_id);
+    }
+    reportln!("Samples: {}", samples.len());
+    reportln!(
+        "Wall clock: mean {:?}, median {:?}, p99 {:?}",
+        stats::mean(&wall_clock_times).unwrap_or_default(),
+        stats::median(&wall_clock_times).unwrap_or_default(),
+        stats::percentile(&wall_clock_times, 99.0).unwrap_or_default()
+    );
+    reportln!(
+        "User time: mean {:?}, System time: mean {:?}",
+        stats::mean(&user_times).unwrap_or_default(),
+        stats::mean(&system_times).unwrap_or_default()
+    );
+    reportln!(
+        "Max RSS: {} bytes",
+        samples
+            .iter()
+            .map(|sample| sample.max_rss)
+            .max()
+            .unwrap_or_default()
+    );
+    reportln!("=======================================");
+    Ok(())
+}
+
+/// Prints the mean, minimum, maximum and standard deviation of the wall-clock time, user time
+/// and CPU usage of the inference samples.
+fn print_inference_summary(samples: &[&Metrics]) {
+    let summary = |name: &str, times: Vec<Duration>| {
+        reportln!(
+            "{}: mean {:?}, min {:?}, max {:?}, stddev {:?}",
+            name,
+            stats::mean(&times).unwrap_or_default(),
+            times.iter().min().cloned().unwrap_or_default(),
+            times.iter().max().cloned().unwrap_or_default(),
+            stats::stddev(&times).unwrap_or_default()
+        );
+    };
+    let cpu_usages: Vec<f64> = samples
+        .iter()
+        .map(|metrics| metrics.cpu_usage as f64)
+        .collect();
+
+    reportln!("============= Inference Summary =============");
+    reportln!("Samples: {}", samples.len());
+    summary(
+        "Wall clock",
+        samples
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect(),
+    );
+    summary(
+        "User time",
+        samples.iter().map(|metrics| metrics.user_time).collect(),
+    );
+    reportln!(
+        "CPU usage: mean {:.1}%, min {:.1}%, max {:.1}%, stddev {:.1}%",
+        stats::mean_f64(&cpu_usages).unwrap_or_default(),
+        cpu_usages
+            .iter()
+            .cloned()
+            .reduce(f64::min)
+            .unwrap_or_default(),
+        cpu_usages
+            .iter()
+            .cloned()
+            .reduce(f64::max)
+            .unwrap_or_default(),
+        stats::stddev_f64(&cpu_usages).unwrap_or_default()
+    );
+    reportln!("=======================================");
+}
+
+/// One statistic of the inference times, compared against a baseline run.
+struct BaselineCheck {
+    metric: &'static str,
+    baseline: Duration,
+    current: Duration,
+    /// Change from the baseline in percent, positive when slower.
+    change: f64,
+    regressed: bool,
+}
+
+/// A statistic of durations, `None` without samples.
+type DurationStatistic = fn(&[Duration]) -> Option<Duration>;
+
+/// Compares the median and p99 of the inference times against the samples of a baseline run,
+/// a binary samples file written by `--export-samples`. A statistic more than `max_regression`
+/// percent slower than the baseline has regressed.
+fn check_baseline(
+    path: &str,
+    inference_times: &[Duration],
+    max_regression: f64,
+) -> Result<Vec<BaselineCheck>, Box<dyn Error>> {
+    let (_, samples) = samples::read(path)?;
+    let baseline_times: Vec<Duration> = samples
+        .iter()
+        .map(|sample| sample.wall_clock_time)
+        .collect();
+
+    let statistics: [(&'static str, DurationStatistic); 2] = [
+        ("median inference", stats::median),
+        ("p99 inference", |durations| {
+            stats::percentile(durations, 99.0)
+        }),
+    ];
+    statistics
+        .into_iter()
+        .map(|(metric, statistic)| {
+            let (Some(baseline), Some(current)) =
+                (statistic(&baseline_times), statistic(inference_times))
+            else {
+                return Err(format!("No inference samples to compare for the {}", metric).into());
+            };
+            let change: f64 = (current.as_secs_f64() / baseline.as_secs_f64() - 1.0) * 100.0;
+            Ok(BaselineCheck {
+                metric,
+                baseline,
+                current,
+                change,
+                regressed: change > max_regression,
+            })
+        })
+        .collect()
+}
+
+/// The baseline comparison with its result and an annotation for GitHub Actions, and the exit
+/// status of the run: 1 if any statistic regressed, else 0.
+fn baseline_report(checks: &[BaselineCheck], max_regression: f64, path: &str) -> (String, i32) {
+    let mut report: String = String::from("============= Baseline Comparison =============\n");
+    for check in checks {
+        let _ = writeln!(
+            report,
+            "{}: {:?} -> {:?} ({:+.1}%){}",
+            check.metric,
+            check.baseline,
+            check.current,
+            check.change,
+            if check.regressed { " REGRESSED" } else { "" }
+        );
+    }
+    let regressions: Vec<String> = checks
+        .iter()
+        .filter(|check| check.regressed)
+        .map(|check| {
+            format!(
+                "{} {:?} -> {:?} ({:+.1}%)",
+                check.metric, check.baseline, check.current, check.change
+            )
+        })
+        .collect();
+    if regressions.is_empty() {
+        report.push_str("Result: PASS\n");
+        let _ = writeln!(
+            report,
+            "::notice title=Benchmark::no regression over {}% against {}",
+            max_regression, path
+        );
+    } else {
+        report.push_str("Result: FAIL\n");
+        let _ = writeln!(
+            report,
+            "::error title=Benchmark regression::{}",
+            regressions.join(", ")
+        );
+    }
+    report.push_str("=======================================\n");
+    (report, if regressions.is_empty() { 0 } else { 1 })
+}
+
+/// Makes a panic write the operations completed so far to `path` before the usual panic
+/// message, since the wasm module aborts on panic and the tracker is lost.
+fn flush_partial_results_on_panic(path: String) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // try_lock, as the panic may have happened while the metrics were locked
+        if let Ok(partial_metrics) = PARTIAL_METRICS.try_lock() {
+            let report: String = partial_metrics
+                .iter()
+                .map(|metrics| metrics.to_string())
+                .collect();
+            match fs::write(&path, report) {
+                Ok(()) => reportln!("Partial results written to {}", path),
+                Err(error) => reportln!("Error: {}", error),
+            }
+        }
+        default_hook(info);
+    }));
+}
+
+/// Prints the metrics of the run, as one line with `--format oneline`, as a JSON object with
+/// `--format json` and as the operation and phase blocks otherwise.
+fn print_metrics(
+    tracker: &BenchmarkTracker,
+    options: &cli::Options,
+    model_path: &str,
+    output: Option<i32>,
+) {
+    if options.format == cli::Format::Json {
+        print!(
+            "{}",
+            export::metrics_json(tracker, options.run_id.as_deref())
+        );
+    } else if options.format == cli::Format::Oneline {
+        let model_name: &str = std::path::Path::new(model_path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(model_path);
+        reportln!(
+            "{}",
+            export::oneline_summary(
+                model_name,
+                &tracker.get_total_metrics(),
+                &tracker.operation_samples("Inference"),
+                output,
+                options.run_id.as_deref()
+            )
+        );
+    } else {
+        if let Some(run_id) = &options.run_id {
+            reportln!("Run ID: {}", run_id);
+        }
+        match options.group_by {
+            cli::GroupBy::Operation => tracker.print_all_metrics(),
+            cli::GroupBy::Phase => tracker.print_grouped_metrics(),
+        }
+    }
+}
+
+/// Writes the waterfall chart and the SQL, hyperfine and samples exports that were asked for.
+/// With `--time-exports`, the time each export took is printed apart from the metrics, which
+/// are complete by then, so that serializing many samples isn't mistaken for inference time.
+fn write_exports(
+    tracker: &BenchmarkTracker,
+    options: &cli::Options,
+    model_path: &str,
+    image_path: &str,
+) {
+    let mut export_times: Vec<(&str, Duration)> = Vec::new();
+
+    if let Some(path) = &options.waterfall_svg {
+        let start: Instant = Instant::now();
+        if let Err(error) = export::write_waterfall_svg(tracker, path) {
+            reportln!("Error: {}", error);
+        }
+        export_times.push(("Waterfall SVG", start.elapsed()));
+    }
+
+    if let Some(path) = &options.export_sql {
+        let start: Instant = Instant::now();
+        let total: Metrics = tracker.get_total_metrics();
+        if let Err(error) =
+            export::append_run_sql(tracker, &total, model_path, image_path, options, path)
+        {
+            reportln!("Error: {}", error);
+        }
+        export_times.push(("SQL", start.elapsed()));
+    }
+
+    if let Some(path) = &options.export_hyperfine {
+        let start: Instant = Instant::now();
+        let command: String = format!("inference {}", model_path);
+        let report: String =
+            export::hyperfine_json(&command, &tracker.operation_samples("Inference"));
+        if let Err(error) = fs::write(path, report) {
+            reportln!("Error: {}", error);
+        }
+        export_times.push(("Hyperfine", start.elapsed()));
+    }
+
+    if let Some(path) = &options.export_samples {
+        let start: Instant = Instant::now();
+        if let Err(error) = export::write_samples(
+            &tracker.operation_samples("Inference"),
+            options.run_id.as_deref(),
+            path,
+        ) {
+            reportln!("Error: {}", error);
+        }
+        export_times.push(("Samples", start.elapsed()));
+    }
+
+    if options.time_exports && !export_times.is_empty() {
+        reportln!("============= Export Time =============");
+        for (export, time) in &export_times {
+            reportln!("{}: {:?}", export, time);
+        }
+        reportln!(
+            "Total: {:?}",
+            export_times.iter().map(|(_, time)| *time).sum::<Duration>()
+        );
+        reportln!("=======================================");
+    }
+}
+
+/// Drives the tracker through the phases and operations of a real run without a model, every
+/// operation sleeping for `sleep`, so that the report and the exports can be checked on their
+/// own.
+fn dry_metrics(tracker: &mut BenchmarkTracker, options: &cli::Options, sleep: Duration) {
+    tracker.start_phase("RED BOX Phase");
+    for name in ["loadmodel", "envload", "readimg"] {
+        let _operation = tracker.operation(name);
+        std::thread::sleep(sleep);
+    }
+    tracker.end_phase("RED BOX Phase");
+
+    tracker.start_phase("GREEN BOX Phase");
+    {
+        let _operation = tracker.operation("Pre-processing");
+        std::thread::sleep(sleep);
+    }
+    for iteration in 0..options.iterations {
+        if iteration > 0 {
+            if tracker.over_budget() {
+                break;
+            }
+            tracker.sleep_untracked(options.cooldown);
+        }
+        tracker.start_tagged_operation("Inference", &options.tags);
+        std::thread::sleep(sleep);
+        tracker.finish_operation();
+    }
+    {
+        let _operation = tracker.operation("Post-processing");
+        std::thread::sleep(sleep);
+    }
+    tracker.end_phase("GREEN BOX Phase");
+}
+
+const CALIBRATION_ROUNDS: usize = 1000;
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Exit code of a warning turned into an error by `--strict`, apart from the 1 of a failed run
+/// and the 2 of a usage error.
+const STRICT_EXIT_CODE: i32 = 3;
+
+const FIRST_INFERENCE: &str = "First inference (includes lazy init)";
+
+#[cfg_attr(not(test), no_mangle)]
+pub fn main() {
+    let options: cli::Options = match cli::Options::parse(std::env::args()) {
+        Ok(options) => options,
+        Err(error) => {
+            println!("Error: {}", error);
+            print!("{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    JSON_OUTPUT.store(options.format == cli::Format::Json, Ordering::Relaxed);
+    if let Some(path) = &options.summarize_samples {
+        if let Err(error) = summarize_samples(path) {
+            reportln!("Error: {}", error);
+        }
+        return;
+    }
+
+    let model_path: String = options.model.clone();
+    let image_path: String = options.image.clone();
+
+    // without labels the classes are printed as indices, and a missing default file is expected
+    let labels: HashMap<usize, String> = match &options.labels {
+        Some(path) => match labels::read_labels(path) {
+            Ok(labels) => labels,
+            Err(error) => {
+                warn_or_exit(
+                    &options,
+                    &format!(
+                        "could not read the labels, printing class indices: {}",
+                        error
+                    ),
+                );
+                HashMap::new()
+            }
+        },
+        None => labels::read_labels(cli::DEFAULT_LABELS).unwrap_or_default(),
+    };
+
+    let mut tracker: BenchmarkTracker = BenchmarkTracker::new();
+    tracker.budget = options.max_duration;
+    tracker.show_start_offsets = options.start_offsets;
+    if options.calibrate_overhead {
+        tracker.calibrate_overhead(CALIBRATION_ROUNDS);
+        reportln!(
+            "Measurement overhead: {:?} per operation, subtracted from the wall-clock times",
+            tracker.overhead
+        );
+    }
+    if let Some(path) = &options.partial_results {
+        tracker.record_partial = true;
+        flush_partial_results_on_panic(path.clone());
+    }
+
+    if let Some(sleep) = options.dry_metrics {
+        dry_metrics(&mut tracker, &options, sleep);
+        print_metrics(&tracker, &options, &model_path, None);
+        write_exports(&tracker, &options, &model_path, &image_path);
+        return;
+    }
+
+    // the warm-up reads are an operation of their own, before any phase
+    let page_cache: Option<(usize, u64)> = options.warm_page_cache.then(|| {
+        let image_read: bool = options.input_npy.is_none() && !options.synthetic_input;
+        let image_path: Option<&str> = image_read.then_some(image_path.as_str());
+        exit_on_error(warm_page_cache(
+            &mut tracker,
+            image_path,
+            options.image_dir.as_deref(),
+        ))
+    });
+
+    // RED BOX: Environment setup, image loading, processing, and model loading
+    tracker.start_phase("RED BOX Phase");
+
+    tracker.start_operation("loadmodel");
+    let model: Graph = match load_model(
+        model_path.as_str(),
+        options.target.execution_target(),
+        options.model_format,
+    ) {
+        Ok(model) => model,
+        Err(error) => {
+            // the backend doesn't say why, but a target other than the CPU or a format the host
+            // has no backend for are the usual causes
+            reportln!(
+                "Error: could not load {} for the {:?} target: {}",
+                model_path,
+                options.target,
+                error
+            );
+            std::process::exit(1);
+        }
+    };
+    tracker.finish_operation();
+
+    tracker.start_operation("envload");
+    let mut context: GraphExecutionContext<'_> = initialize_env(&model).unwrap();
+    tracker.finish_operation();
+
+    // wasi-nn doesn't report the input shape, so it is read from the model file itself
+    let model_input_shape: Option<Vec<Option<usize>>> = if matches!(
+        resolve_model_format(&model_path, options.model_format),
+        Ok(cli::ModelFormat::Onnx)
+    ) {
+        match fs::read(&model_path)
+            .map_err(Box::from)
+            .and_then(|model| onnx::input_shape(&model))
+        {
+            Ok(shape) => shape,
+            Err(error) => {
+                warn_or_exit(
+                    &options,
+                    &format!("could not read the model input shape: {}", error),
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // a pre-decoded .npy tensor replaces the image and its preprocessing
+    let npy_input: Option<npy::NpyArray> = options.input_npy.as_ref().map(|path| {
+        let _operation = tracker.operation("readnpy");
+        exit_on_error(npy::read_npy(path))
+    });
+
+    let original_img: Option<ImageBuffer<Rgba<u8>, Vec<u8>>> =
+        if npy_input.is_none() && !options.synthetic_input {
+            let _operation = tracker.operation("readimg");
+            Some(read_img(image_path.as_str(), &options.preprocess).unwrap())
+        } else {
+            None
+        };
+
+    tracker.end_phase("RED BOX Phase");
+
+    // GREEN BOX: Model inference and post-processing
+    tracker.start_phase("GREEN BOX Phase");
+
+    tracker.start_operation("Pre-processing");
+    let input: InputTensor = match (npy_input, original_img) {
+        (Some(array), _) => InputTensor {
+            dtype: array.dtype,
+            shape: array.shape,
+            data: array.data,
+        },
+        (None, Some(original_img)) => InputTensor {
+            dtype: options.input_dtype,
+            shape: options.preprocess.input_shape(),
+            data: exit_on_error(process_image(original_img, &options)),
+        },
+        // nothing was read, so only the compute path is measured
+        (None, None) => synthetic_tensor(options.input_dtype, options.preprocess.input_shape()),
+    };
+    if let Err(error) = input.set_input(&mut context) {
+        reportln!("Error: {}", error);
+        std::process::exit(1);
+    }
+    tracker.finish_operation();
+
+    if let Some(model_input_shape) = &model_input_shape {
+        exit_on_error(onnx::check_input_shape(model_input_shape, &input.shape));
+    }
+
+    if !options.warmup.is_zero() || options.warmup_iterations > 0 {
+        exit_on_error(warm_up(&mut tracker, &mut context, &options));
+    }
+
+    let mut predictions: Vec<(i32, f32)> = Vec::new();
+    let mut output_buffer: Vec<f32> = Vec::new();
+    for iteration in 0..options.iterations {
+        if iteration > 0 {
+            // at least one inference runs, for the post-processing
+            if tracker.over_budget() {
+                break;
+            }
+            tracker.sleep_untracked(options.cooldown);
+        }
+
+        // the first compute can include lazy graph optimization, so it is kept apart from the
+        // steady-state samples when asked to
+        let name: &str = if options.cold_start && iteration == 0 {
+            FIRST_INFERENCE
+        } else {
+            "Inference"
+        };
+        tracker.start_tagged_operation(name, &options.tags);
+        // a failed inference has no time to report, and its output would be stale
+        if let Err(error) = run_model_with_retries(&mut tracker, &mut context, options.retries) {
+            reportln!("Error: inference {} failed: {}", iteration + 1, error);
+            std::process::exit(1);
+        }
+        tracker.finish_operation();
+
+        if options.assert_stable {
+            predictions.push(exit_on_error(top_prediction(
+                &mut context,
+                &options,
+                &mut output_buffer,
+            )));
+        }
+    }
+
+    if options.assert_stable {
+        exit_on_error(postprocess::check_stable(&predictions, options.tolerance));
+    }
+
+    tracker.start_operation("Post-processing");
+    let output: Option<i32> =
+        match post_process(&mut context, image_path.as_str(), &labels, &options) {
+            Ok(output) => output,
+            Err(error) => {
+                reportln!("Error: {}", error);
+                std::process::exit(1);
+            }
+        };
+    tracker.finish_operation();
+
+    tracker.end_phase("GREEN BOX Phase");
+
+    let batch_throughput: Option<Vec<(usize, f64)>> = options.batch_sweep.map(|max_batch_size| {
+        tracker.start_phase("Batch Sweep Phase");
+        let throughput = batch_sweep(&mut tracker, &mut context, &input, &options, max_batch_size);
+        tracker.end_phase("Batch Sweep Phase");
+        exit_on_error(throughput)
+    });
+
+    let target_comparison: Option<Vec<TargetComparison>> =
+        options.targets.as_ref().map(|targets| {
+            tracker.start_phase("Target Comparison Phase");
+            let comparison = compare_targets(targets, &model_path, &input, &options);
+            tracker.end_phase("Target Comparison Phase");
+            comparison
+        });
+
+    let format_decodes: Option<Vec<FormatDecode>> = if options.decode_formats {
+        tracker.start_phase("Decode Formats Phase");
+        let decodes = decode_formats(&mut tracker, &image_path, &options);
+        tracker.end_phase("Decode Formats Phase");
+        Some(exit_on_error(decodes))
+    } else {
+        None
+    };
+
+    let frame_stream_throughput: Option<f64> = options.frame_pipe.as_ref().map(|path| {
+        tracker.start_phase("Frame Stream Phase");
+        let throughput = frame_stream(&mut tracker, &mut context, path, &options);
+        tracker.end_phase("Frame Stream Phase");
+        exit_on_error(throughput)
+    });
+
+    let tensor_file_throughput: Option<f64> = options.tensor_file.as_ref().map(|path| {
+        tracker.start_phase("Tensor File Phase");
+        let throughput = tensor_file_sweep(&mut tracker, &mut context, path, &options);
+        tracker.end_phase("Tensor File Phase");
+        exit_on_error(throughput)
+    });
+
+    let image_sweep: Option<ImageSweep> = options.image_dir.as_ref().map(|dir| {
+        tracker.start_phase("Image Sweep Phase");
+        let sweep = image_sweep(&mut tracker, &mut context, dir, &options);
+        tracker.end_phase("Image Sweep Phase");
+        exit_on_error(sweep)
+    });
+
+    if tracker.budget_exceeded {
+        reportln!(
+            "Stopped early after the time budget of {:?}, the report covers the completed work",
+            options.max_duration.unwrap_or_default()
+        );
+    }
+
+    for problem in tracker.check_consistency(&tracker.get_total_metrics()) {
+        warn_or_exit(&options, &problem);
+    }
+
+    print_metrics(&tracker, &options, &model_path, output);
+
+    if options.format == cli::Format::Text {
+        match page_cache {
+            Some((files, bytes)) => {
+                reportln!("Page cache: warmed with {} files, {} bytes", files, bytes)
+            }
+            None => reportln!("Page cache: not warmed, the first read of each image may be cold"),
+        }
+
+        if let Some((preprocessing, inference, ratio)) = bottleneck(&tracker) {
+            reportln!("============= Bottleneck =============");
+            reportln!(
+                "Preprocessing per image {:?} / mean inference {:?} = {:.*}: {}",
+                preprocessing,
+                inference,
+                options.precision,
+                ratio,
+                bottleneck_kind(ratio)
+            );
+            reportln!("=======================================");
+        }
+    }
+
+    let inference_samples: Vec<&Metrics> = tracker.operation_samples("Inference");
+    if options.format == cli::Format::Text && inference_samples.len() > 1 {
+        print_inference_summary(&inference_samples);
+    }
+
+    if let Some(buckets) = options.histogram {
+        reportln!("============= Inference Histogram =============");
+        report!(
+            "{}",
+            export::histogram_text(&tracker.operation_samples("Inference"), buckets)
+        );
+        reportln!("=======================================");
+    }
+
+    if options.cold_start {
+        let steady_state: Vec<&Metrics> = tracker.operation_samples("Inference");
+        reportln!("============= Cold Start =============");
+        for first in tracker.operation_samples(FIRST_INFERENCE) {
+            reportln!("{}: {:?}", FIRST_INFERENCE, first.wall_clock_time);
+        }
+        if steady_state.is_empty() {
+            reportln!("Steady-state inference: n/a, run more than one iteration");
+        } else {
+            let total: Duration = steady_state
+                .iter()
+                .map(|metrics| metrics.wall_clock_time)
+                .sum();
+            reportln!(
+                "Steady-state inference: {:?} (mean of {})",
+                total / steady_state.len() as u32,
+                steady_state.len()
+            );
+        }
+        reportln!("=======================================");
+    }
+
+    let ground_truth: HashMap<String, i32> = match &options.ground_truth {
+        Some(path) => exit_on_error(postprocess::read_ground_truth(path)),
+        None => HashMap::new(),
+    };
+    let model_comparison: Option<Vec<ModelComparison>> =
+        match (&options.compare_models, &options.image_dir) {
+            (Some(models), Some(dir)) => Some(exit_on_error(compare_models(
+                models,
+                dir,
+                &ground_truth,
+                &options,
+            ))),
+            _ => None,
+        };
+
+    let size_comparison: Option<Vec<SizeComparison>> =
+        match (&options.size_sweep, &options.image_dir) {
+            (Some(sizes), Some(dir)) => Some(size_sweep(&mut context, sizes, dir, &options)),
+            _ => None,
+        };
+
+    if let Some(image_sweep) = &image_sweep {
+        reportln!("============= Image Sweep =============");
+        for item in &image_sweep.items {
+            reportln!("{}: {} (score: {})", item.path, item.class, item.score);
+        }
+        reportln!(
+            "Images: {}, {:.*} img/s",
+            image_sweep.items.len(),
+            options.precision,
+            image_sweep.images_per_second
+        );
+        reportln!("=======================================");
+    }
+
+    if let Some(model_comparison) = &model_comparison {
+        reportln!("============= Model Comparison =============");
+        for comparison in model_comparison {
+            reportln!(
+                "{}: top-1 accuracy {}, median inference {:?}",
+                comparison.model,
+                accuracy_text(comparison.accuracy),
+                comparison.median_inference.unwrap_or_default()
+            );
+        }
+        // the geometric mean weighs a model twice as fast as much as one twice as slow
+        let medians: Vec<Duration> = model_comparison
+            .iter()
+            .filter_map(|comparison| comparison.median_inference)
+            .collect();
+        if let (Some(geometric_mean), Some(mean)) =
+            (stats::geometric_mean(&medians), stats::mean(&medians))
+        {
+            reportln!(
+                "Median inference across models: geometric mean {:?}, arithmetic mean {:?}",
+                geometric_mean,
+                mean
+            );
+        }
+        reportln!("=======================================");
+    }
+
+    if let Some(size_comparison) = &size_comparison {
+        reportln!("============= Size Sweep =============");
+        for comparison in size_comparison {
+            let (width, height) = comparison.size;
+            match &comparison.sweep {
+                Ok(sweep) => reportln!(
+                    "{}x{}: {:.*} img/s, median inference {:?}, top-1 accuracy {}",
+                    width,
+                    height,
+                    options.precision,
+                    sweep.images_per_second,
+                    stats::median(&sweep.inference_times()).unwrap_or_default(),
+                    accuracy_text(sweep.accuracy(&ground_truth))
+                ),
+                Err(error) => reportln!("{}x{}: n/a ({})", width, height, error),
+            }
+        }
+        reportln!("=======================================");
+    }
+
+    if let Some(frame_stream_throughput) = frame_stream_throughput {
+        let latencies: Vec<Duration> = tracker
+            .operation_samples("Frame (stream)")
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+        reportln!("============= Frame Stream =============");
+        reportln!(
+            "Frames: {}, {:.*} frames/s, median latency {:?}, p99 latency {:?}",
+            latencies.len(),
+            options.precision,
+            frame_stream_throughput,
+            stats::median(&latencies).unwrap_or_default(),
+            stats::percentile(&latencies, 99.0).unwrap_or_default()
+        );
+        reportln!("=======================================");
+    }
+
+    if let Some(tensor_file_throughput) = tensor_file_throughput {
+        let inference_times: Vec<Duration> = tracker
+            .operation_samples("Inference (tensor file)")
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+        reportln!("============= Tensor File =============");
+        reportln!(
+            "Tensors: {}, {:.*} inputs/s, median inference {:?}",
+            inference_times.len(),
+            options.precision,
+            tensor_file_throughput,
+            stats::median(&inference_times).unwrap_or_default()
+        );
+        reportln!("=======================================");
+    }
+
+    if let Some(format_decodes) = &format_decodes {
+        reportln!("============= Decode Time by Format =============");
+        for decode in format_decodes {
+            reportln!(
+                "{:?}: {} bytes, median decode {:?}",
+                decode.format,
+                decode.bytes,
+                decode.median_decode.unwrap_or_default()
+            );
+        }
+        reportln!("=======================================");
+    }
+
+    if let Some(target_comparison) = &target_comparison {
+        reportln!("============= Execution Targets =============");
+        for comparison in target_comparison {
+            match &comparison.median_inference {
+                Ok(median) => reportln!("{:?}: median inference {:?}", comparison.target, median),
+                Err(error) => reportln!("{:?}: n/a ({})", comparison.target, error),
+            }
+        }
+        reportln!("=======================================");
+    }
+
+    if let (Some(path), Some(image_sweep)) = (&options.sweep_report, &image_sweep) {
+        let report: String = export::sweep_report_json(
+            options.run_id.as_deref(),
+            image_sweep,
+            image_sweep.accuracy(&ground_truth),
+            model_comparison.as_deref(),
+        );
+        if let Err(error) = fs::write(path, report) {
+            reportln!("Error: {}", error);
+        }
+    }
+
+    if options.retries > 0 {
+        reportln!("============= Retries =============");
+        let retried: Vec<&Metrics> = tracker
+            .completed_metrics
+            .iter()
+            .filter(|metrics| metrics.tags.contains_key("retries"))
+            .collect();
+        for metrics in &retried {
+            reportln!("{}: {} retries", metrics.name, metrics.tags["retries"]);
+        }
+        reportln!("Retried operations: {}", retried.len());
+        reportln!("=======================================");
+    }
+
+    if options.unaccounted {
+        reportln!("============= Unaccounted Time =============");
+        for (gap, duration) in tracker.unaccounted_time(&tracker.get_total_metrics()) {
+            reportln!("{}: {:?}", gap, duration);
+        }
+        reportln!("=======================================");
+    }
+
+    if let Some(batch_throughput) = &batch_throughput {
+        reportln!("============= Batch Sweep =============");
+        for (batch_size, images_per_second) in batch_throughput {
+            reportln!(
+                "Batch {}: {:.*} img/s",
+                batch_size,
+                options.precision,
+                images_per_second
+            );
+        }
+        reportln!("=======================================");
+    }
+
+    write_exports(&tracker, &options, &model_path, &image_path);
+
+    if options.format == cli::Format::Text {
+        match output {
+            Some(class) => reportln!("Predicted Class Index: {}", class),
+            None => reportln!("Predicted Class Index: unknown"),
+        }
+    }
+
+    if let Some(path) = &options.class_file {
+        let class: String = output.map_or("unknown".to_string(), |class| class.to_string());
+        if let Err(error) = fs::write(path, class + "\n") {
+            reportln!("Error: {}", error);
+        }
+    }
+
+    if let Some(dir) = &options.annotate_dir {
+        // without a decoded image there is nothing to annotate but the sweep
+        let image_read: bool = options.input_npy.is_none() && !options.synthetic_input;
+        let mut predictions: Vec<(&str, i32)> = output
+            .filter(|_| image_read)
+            .map(|class| (image_path.as_str(), class))
+            .into_iter()
+            .collect();
+        if let Some(image_sweep) = &image_sweep {
+            predictions.extend(
+                image_sweep
+                    .items
+                    .iter()
+                    .map(|item| (item.path.as_str(), item.class)),
+            );
+        }
+        if let Err(error) = write_annotated_images(&predictions, &labels, dir) {
+            reportln!("Error: {}", error);
+        }
+    }
+
+    if let Some(path) = &options.bundle {
+        let args: Vec<String> = std::env::args().collect();
+        if let Err(error) = fs::write(path, export::bundle_json(&args, &options)) {
+            reportln!("Error: {}", error);
+        }
+    }
+
+    if let Some(path) = &options.guest_timing {
+        // the host sleeps through the cooldowns as well, so they count here
+        let total: Duration = tracker.get_total_metrics().wall_clock_time + tracker.untracked_time;
+        let inference: Duration = tracker
+            .operation_samples(FIRST_INFERENCE)
+            .iter()
+            .chain(tracker.operation_samples("Inference").iter())
+            .map(|metrics| metrics.wall_clock_time)
+            .sum();
+        let timing: String = format!(
+            "total_ns {}\ninference_ns {}\n",
+            total.as_nanos(),
+            inference.as_nanos()
+        );
+        if let Err(error) = fs::write(path, timing) {
+            reportln!("Error: {}", error);
+        }
+    }
+
+    // a CI gate: the summary, an annotation for GitHub Actions and a failing exit code
+    if let Some(path) = &options.baseline {
+        let inference_times: Vec<Duration> = tracker
+            .operation_samples("Inference")
+            .iter()
+            .map(|metrics| metrics.wall_clock_time)
+            .collect();
+        let checks: Vec<BaselineCheck> =
+            match check_baseline(path, &inference_times, options.max_regression) {
+                Ok(checks) => checks,
+                Err(error) => {
+                    reportln!("Error: {}", error);
+                    std::process::exit(1);
+                }
+            };
+
+        let (report, status) = baseline_report(&checks, options.max_regression, path);
+        report!("{}", report);
+        if status != 0 {
+            std::process::exit(status);
+        }
+    }
+
+    if options.class_exit_code {
+        // exit codes are a byte, so 255 stands for an unknown class and any class that doesn't fit
+        let code: i32 = match output {
+            Some(class) if class >= 255 => {
+                warn_or_exit(
+                    &options,
+                    &format!("class {} doesn't fit an exit code, exiting with 255", class),
+                );
+                255
+            }
+            Some(class) => class,
+            None => 255,
+        };
+        std::process::exit(code);
+    }
+
+    // let number_threads: NonZero<usize> = num_threads().unwrap();
+    // println!("Number of Threads: {:?}", number_threads);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    /// A wasi-nn backend error with the errno of the `nn_errno` enum of the WITX specification.
+    fn backend_error(errno: i32) -> wasi_nn::Error {
+        wasi_nn::Error::BackendError(errno.into())
+    }
+
+    /// Stands in for a wasi-nn execution context: records the input shapes and the computes,
+    /// and returns `output` like a backend that reports a too small buffer.
+    #[derive(Default)]
+    struct FakeContext {
+        input_shapes: Vec<Vec<usize>>,
+        computes: usize,
+        output: Vec<f32>,
+    }
+
+    impl Inference for FakeContext {
+        fn set_input(
+            &mut self,
+            _tensor_type: TensorType,
+            shape: &[usize],
+            _data: &[u8],
+        ) -> Result<(), wasi_nn::Error> {
+            self.input_shapes.push(shape.to_vec());
+            Ok(())
+        }
+
+        fn compute(&mut self) -> Result<(), wasi_nn::Error> {
+            self.computes += 1;
+            Ok(())
+        }
+
+        fn get_output(&mut self, buffer: &mut [f32]) -> Result<usize, wasi_nn::Error> {
+            const TOO_LARGE: i32 = 7;
+            if buffer.len() < self.output.len() {
+                return Err(backend_error(TOO_LARGE));
+            }
+            buffer[..self.output.len()].copy_from_slice(&self.output);
+            Ok(std::mem::size_of_val(self.output.as_slice()))
+        }
+    }
+
+    /// Options with a 4x4 input, so that the tests preprocess tiny images.
+    fn small_input_options() -> cli::Options {
+        let mut options = cli::Options::default();
+        options.preprocess.size = (4, 4);
+        options
+    }
+
+    /// An empty directory of its own for a test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wasi-nn-module-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A directory of `count` PNG images named `0.png`, `1.png`, ...
+    fn image_dir(name: &str, count: usize) -> String {
+        let dir = test_dir(name);
+        for index in 0..count {
+            fs::write(
+                dir.join(format!("{}.png", index)),
+                encoded_image(image::ImageFormat::Png),
+            )
+            .unwrap();
+        }
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn encoded_image(format: image::ImageFormat) -> Vec<u8> {
+        let image =
+            image::DynamicImage::ImageRgb8(ImageBuffer::from_pixel(8, 6, Rgb([200, 100, 50])));
+        let mut encoded: std::io::Cursor<Vec<u8>> = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut encoded, format).unwrap();
+        encoded.into_inner()
+    }
+
+    fn metrics(name: &str, wall_clock_ms: u64) -> Metrics {
+        Metrics {
+            name: name.to_string(),
+            timestamp: Instant::now(),
+            wall_clock_time: Duration::from_millis(wall_clock_ms),
+            user_time: Duration::ZERO,
+            system_time: Duration::ZERO,
+            max_rss: 0,
+            cpu_usage: 0.0,
+            tags: BTreeMap::new(),
+            allocations: 0,
+            allocated_bytes: 0,
+            overhead: Duration::ZERO,
+        }
+    }
+
+    fn tensor_values(bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn image_to_tensor_normalizes_with_the_mean_and_std() {
+        let image = ImageBuffer::from_pixel(2, 1, Rgb([255u8, 51, 0]));
+        let preprocess = cli::PreprocessConfig {
+            size: (2, 1),
+            mean: [0.5, 0.2, 0.0],
+            std: [0.5, 0.1, 2.0],
+            ..cli::PreprocessConfig::default()
+        };
+
+        let bytes = image_to_tensor(image, cli::InputDtype::F32, None, &preprocess).unwrap();
+        // NCHW: both pixels of the red channel, then green, then blue
+        let expected: [f32; 6] = [1.0, 1.0, 0.0, 0.0, 0.0, 0.0];
+        for (value, expected) in tensor_values(&bytes).iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-6, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn image_to_tensor_applies_the_mean_and_std_in_rgb_order_for_bgr() {
+        let image = ImageBuffer::from_pixel(1, 1, Rgb([255u8, 0, 0]));
+        let preprocess = cli::PreprocessConfig {
+            size: (1, 1),
+            mean: [0.0, 0.0, 0.5],
+            std: [0.5, 1.0, 1.0],
+            channel_order: cli::ChannelOrder::Bgr,
+            ..cli::PreprocessConfig::default()
+        };
+
+        let bytes = image_to_tensor(image, cli::InputDtype::F32, None, &preprocess).unwrap();
+        // blue first, normalized with the blue mean; red last, divided by the red std
+        assert_eq!(tensor_values(&bytes), vec![-0.5, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn image_to_tensor_keeps_raw_values_for_u8() {
+        let image = ImageBuffer::from_pixel(1, 1, Rgb([10u8, 20, 30]));
+        let preprocess = cli::PreprocessConfig {
+            size: (1, 1),
+            ..cli::PreprocessConfig::default()
+        };
+
+        let bytes = image_to_tensor(image, cli::InputDtype::U8, None, &preprocess).unwrap();
+        assert_eq!(bytes, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn consistent_accounting_has_no_problems() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker
+            .phase_metrics
+            .push(("Setup".to_string(), metrics("Setup", 40)));
+        tracker.completed_metrics = vec![metrics("Load", 30), metrics("Inference", 60)];
+
+        assert!(tracker.check_consistency(&metrics("Total", 100)).is_empty());
+    }
+
+    #[test]
+    fn phase_longer_than_the_total_is_a_problem() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker
+            .phase_metrics
+            .push(("Setup".to_string(), metrics("Setup", 150)));
+
+        let problems: Vec<String> = tracker.check_consistency(&metrics("Total", 100));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("Setup takes 150ms"));
+    }
+
+    #[test]
+    fn operations_adding_up_to_more_than_the_total_are_a_problem() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.completed_metrics = vec![metrics("Load", 60), metrics("Inference", 60)];
+
+        let problems: Vec<String> = tracker.check_consistency(&metrics("Total", 100));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("the operations take 120ms"));
+    }
+
+    #[test]
+    fn nested_operations_are_left_out_of_the_sum() {
+        let mut tracker = BenchmarkTracker::new();
+        let mut nested: Metrics = metrics("Preprocess", 50);
+        nested
+            .tags
+            .insert("parent".to_string(), "Inference".to_string());
+        tracker.completed_metrics = vec![nested, metrics("Inference", 80)];
+
+        assert!(tracker.check_consistency(&metrics("Total", 100)).is_empty());
+    }
+
+    #[test]
+    fn too_large_is_only_the_too_large_errno() {
+        const TOO_LARGE: i32 = 7;
+        const RUNTIME_ERROR: i32 = 5;
+        assert!(is_too_large(&backend_error(TOO_LARGE)));
+        assert!(!is_too_large(&backend_error(RUNTIME_ERROR)));
+        assert!(!is_too_large(&wasi_nn::Error::IoError(
+            std::io::ErrorKind::OutOfMemory.into()
+        )));
+    }
+
+    #[test]
+    fn two_frames_are_two_inferences() {
+        let mut stream: Vec<u8> = Vec::new();
+        for _ in 0..2 {
+            let frame: Vec<u8> = encoded_image(image::ImageFormat::Png);
+            stream.extend((frame.len() as u32).to_le_bytes());
+            stream.extend(frame);
+        }
+        let mut tracker = BenchmarkTracker::new();
+        let mut context = FakeContext::default();
+
+        stream_frames(
+            &mut tracker,
+            &mut context,
+            &mut stream.as_slice(),
+            "frames",
+            &small_input_options(),
+        )
+        .unwrap();
+        assert_eq!(context.computes, 2);
+        assert_eq!(context.input_shapes, vec![vec![1, 3, 4, 4]; 2]);
+        assert_eq!(tracker.operation_samples("Frame (stream)").len(), 2);
+    }
+
+    #[test]
+    fn frames_are_read_until_the_end_of_the_stream() {
+        let stream: Vec<u8> = [&3u32.to_le_bytes()[..], b"abc", &1u32.to_le_bytes(), b"d"].concat();
+        let mut reader: &[u8] = &stream;
+        assert_eq!(
+            read_frame(&mut reader, "frames", 0).unwrap(),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(
+            read_frame(&mut reader, "frames", 1).unwrap(),
+            Some(b"d".to_vec())
+        );
+        assert_eq!(read_frame(&mut reader, "frames", 2).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_and_truncated_frames_are_errors() {
+        let oversized: [u8; 4] = u32::MAX.to_le_bytes();
+        let error = read_frame(&mut oversized.as_slice(), "frames", 0).unwrap_err();
+        assert!(
+            error.to_string().contains("claims 4294967295 bytes"),
+            "{}",
+            error
+        );
+
+        let truncated: Vec<u8> = [&4u32.to_le_bytes()[..], b"ab"].concat();
+        let error = read_frame(&mut truncated.as_slice(), "frames", 0).unwrap_err();
+        assert!(
+            error.to_string().contains("frame 0 is truncated"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn two_models_are_scored_on_the_same_images() {
+        let dir: String = image_dir("compare-models", 2);
+        let ground_truth: HashMap<String, i32> =
+            HashMap::from([("0.png".to_string(), 1), ("1.png".to_string(), 1)]);
+        let options = small_input_options();
+
+        let mut right = FakeContext {
+            output: vec![0.1, 0.9, 0.0],
+            ..FakeContext::default()
+        };
+        let mut wrong = FakeContext {
+            output: vec![0.9, 0.1, 0.0],
+            ..FakeContext::default()
+        };
+        let right: ModelComparison =
+            score_model("right.onnx", &mut right, &dir, &ground_truth, &options).unwrap();
+        let wrong: ModelComparison =
+            score_model("wrong.onnx", &mut wrong, &dir, &ground_truth, &options).unwrap();
+
+        assert_eq!(right.model, "right.onnx");
+        assert_eq!(right.accuracy, Some((2, 2)));
+        assert_eq!(wrong.accuracy, Some((0, 2)));
+        assert!(right.median_inference.is_some() && wrong.median_inference.is_some());
+    }
+
+    #[test]
+    fn batch_sweep_to_four_has_three_data_points() {
+        let mut tracker = BenchmarkTracker::new();
+        let mut context = FakeContext::default();
+        let mut options = small_input_options();
+        options.iterations = 2;
+        let input: InputTensor =
+            synthetic_tensor(options.input_dtype, options.preprocess.input_shape());
+
+        let throughput = batch_sweep(&mut tracker, &mut context, &input, &options, 4).unwrap();
+        let batch_sizes: Vec<usize> = throughput
+            .iter()
+            .map(|(batch_size, _)| *batch_size)
+            .collect();
+        assert_eq!(batch_sizes, vec![1, 2, 4]);
+        let batch_dims: Vec<usize> = context.input_shapes.iter().map(|shape| shape[0]).collect();
+        assert_eq!(batch_dims, vec![1, 2, 4]);
+        assert_eq!(context.computes, 6);
+        assert_eq!(tracker.operation_samples("Inference (batch 4)").len(), 2);
+    }
+
+    #[test]
+    fn all_three_formats_are_decoded_and_timed() {
+        let image_path = test_dir("decode-formats").join("image.png");
+        fs::write(&image_path, encoded_image(image::ImageFormat::Png)).unwrap();
+        let mut tracker = BenchmarkTracker::new();
+        let mut options = small_input_options();
+        options.iterations = 2;
+
+        let decodes = decode_formats(&mut tracker, image_path.to_str().unwrap(), &options).unwrap();
+        let formats: Vec<image::ImageFormat> = decodes.iter().map(|decode| decode.format).collect();
+        assert_eq!(
+            formats,
+            vec![
+                image::ImageFormat::Jpeg,
+                image::ImageFormat::Png,
+                image::ImageFormat::WebP
+            ]
+        );
+        for decode in &decodes {
+            assert!(decode.bytes > 0);
+            assert!(decode.median_decode.is_some());
+            let name: String = format!("decode ({:?})", decode.format);
+            assert_eq!(tracker.operation_samples(&name).len(), 2);
+        }
+    }
+
+    #[test]
+    fn decoding_a_missing_image_is_an_error() {
+        let mut tracker = BenchmarkTracker::new();
+        let missing = test_dir("decode-missing").join("missing.png");
+        assert!(decode_formats(
+            &mut tracker,
+            missing.to_str().unwrap(),
+            &small_input_options()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn every_tensor_of_a_tensor_file_is_inferred() {
+        let path = test_dir("tensor-file").join("tensors.npy");
+        fs::write(&path, npy::npy_bytes("|u1", "(3, 1, 2, 2)", &[7; 12])).unwrap();
+        let mut tracker = BenchmarkTracker::new();
+        let mut context = FakeContext::default();
+
+        tensor_file_sweep(
+            &mut tracker,
+            &mut context,
+            path.to_str().unwrap(),
+            &small_input_options(),
+        )
+        .unwrap();
+        assert_eq!(context.input_shapes, vec![vec![1, 2, 2]; 3]);
+        assert_eq!(context.computes, 3);
+        assert_eq!(
+            tracker.operation_samples("Inference (tensor file)").len(),
+            3
+        );
+    }
+
+    #[test]
+    fn tensor_file_without_dimensions_is_an_error() {
+        let path = test_dir("tensor-file-scalar").join("scalar.npy");
+        fs::write(&path, npy::npy_bytes("|u1", "()", &[7])).unwrap();
+        let mut tracker = BenchmarkTracker::new();
+
+        let error = tensor_file_sweep(
+            &mut tracker,
+            &mut FakeContext::default(),
+            path.to_str().unwrap(),
+            &small_input_options(),
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("at least one dimension"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn image_sweep_reuses_one_loaded_model_for_every_image() {
+        let dir: String = image_dir("image-sweep", 3);
+        let mut tracker = BenchmarkTracker::new();
+        // the one context stands for the one loaded model
+        let mut context = FakeContext {
+            output: vec![0.2, 0.8],
+            ..FakeContext::default()
+        };
+
+        let sweep: ImageSweep =
+            image_sweep(&mut tracker, &mut context, &dir, &small_input_options()).unwrap();
+        let paths: Vec<&str> = sweep.items.iter().map(|item| item.path.as_str()).collect();
+        assert_eq!(paths.len(), 3);
+        assert!(paths[0].ends_with("0.png") && paths[2].ends_with("2.png"));
+        assert!(sweep.items.iter().all(|item| item.class == 1));
+        assert_eq!(context.computes, 3);
+        assert_eq!(tracker.operation_samples("Inference (sweep)").len(), 3);
+        assert!(tracker.operation_samples("loadmodel").is_empty());
+    }
+
+    #[test]
+    fn sweeping_a_missing_directory_is_an_error() {
+        let missing = test_dir("image-sweep-missing").join("missing");
+        let result = image_sweep(
+            &mut BenchmarkTracker::new(),
+            &mut FakeContext::default(),
+            missing.to_str().unwrap(),
+            &small_input_options(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn warming_the_page_cache_is_an_operation_of_its_own() {
+        let dir: String = image_dir("warm-page-cache", 2);
+        fs::write(std::path::Path::new(&dir).join("notes.txt"), "not an image").unwrap();
+        let image = test_dir("warm-page-cache-image").join("image.png");
+        fs::write(&image, encoded_image(image::ImageFormat::Png)).unwrap();
+        let mut tracker = BenchmarkTracker::new();
+
+        let (files, bytes) = warm_page_cache(&mut tracker, image.to_str(), Some(&dir)).unwrap();
+        assert_eq!(files, 3);
+        assert_eq!(
+            bytes,
+            3 * encoded_image(image::ImageFormat::Png).len() as u64
+        );
+        assert_eq!(tracker.operation_samples("Warm page cache").len(), 1);
+        assert!(tracker.phase_metrics.is_empty());
+    }
+
+    #[test]
+    fn warming_a_missing_directory_is_an_error() {
+        let missing = test_dir("warm-page-cache-missing").join("missing");
+        assert!(warm_page_cache(&mut BenchmarkTracker::new(), None, missing.to_str()).is_err());
+    }
+
+    #[test]
+    fn rgb_pixels_map_to_the_channel_order() {
+        assert_eq!(
+            pixel_channels::<Rgb<u8>>(cli::ChannelOrder::Rgb).unwrap(),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            pixel_channels::<Rgba<u8>>(cli::ChannelOrder::Bgr).unwrap(),
+            [2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn pixels_without_color_channels_are_detected() {
+        let error = pixel_channels::<image::Luma<u8>>(cli::ChannelOrder::Rgb).unwrap_err();
+        assert!(error.to_string().contains("from Y pixels"), "{}", error);
+        assert!(pixel_channels::<image::LumaA<u8>>(cli::ChannelOrder::Bgr).is_err());
+
+        let gray = ImageBuffer::from_pixel(1, 1, image::Luma([128u8]));
+        let preprocess = cli::PreprocessConfig {
+            size: (1, 1),
+            ..cli::PreprocessConfig::default()
+        };
+        assert!(image_to_tensor(gray, cli::InputDtype::F32, None, &preprocess).is_err());
+    }
+
+    #[test]
+    fn time_based_warmup_runs_for_at_least_the_budget() {
+        let mut tracker = BenchmarkTracker::new();
+        let mut context = FakeContext::default();
+        let mut options = small_input_options();
+        options.warmup = Duration::from_millis(20);
+
+        warm_up(&mut tracker, &mut context, &options).unwrap();
+        let warmup: &Metrics = tracker.operation_samples("Warmup")[0];
+        assert!(warmup.wall_clock_time >= options.warmup);
+        assert_eq!(warmup.tags["iterations"], context.computes.to_string());
+        assert!(context.computes > 1);
+    }
+
+    #[test]
+    fn iteration_based_warmup_runs_the_iterations() {
+        let mut tracker = BenchmarkTracker::new();
+        let mut context = FakeContext::default();
+        let mut options = small_input_options();
+        options.warmup_iterations = 3;
+
+        warm_up(&mut tracker, &mut context, &options).unwrap();
+        assert_eq!(context.computes, 3);
+        assert_eq!(
+            tracker.operation_samples("Warmup")[0].tags["iterations"],
+            "3"
+        );
+    }
+
+    #[test]
+    fn combine_starts_at_the_earliest_timestamp() {
+        let early: Metrics = metrics("Phase", 10);
+        let mut late: Metrics = metrics("Phase", 5);
+        late.timestamp = early.timestamp + Duration::from_millis(20);
+
+        assert_eq!(early.combine(&late).timestamp, early.timestamp);
+        assert_eq!(late.combine(&early).timestamp, early.timestamp);
+        assert_eq!(
+            late.combine(&early).wall_clock_time,
+            Duration::from_millis(15)
+        );
+    }
+
+    #[test]
+    fn reference_check_passes_within_the_tolerance_and_fails_outside() {
+        let dir = test_dir("reference");
+        let reference_path = dir.join("reference.npy");
+        let reference: Vec<u8> = [1.0f32, 3.0, 0.5]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        fs::write(&reference_path, npy::npy_bytes("<f4", "(3,)", &reference)).unwrap();
+        let mut options = small_input_options();
+        options.reference_npy = Some(reference_path.to_str().unwrap().to_string());
+        options.tolerance = 1e-3;
+        let labels: HashMap<usize, String> = HashMap::new();
+
+        let mut context = FakeContext {
+            output: vec![1.0, 3.000_1, 0.5],
+            ..FakeContext::default()
+        };
+        let class = post_process(&mut context, "image.png", &labels, &options).unwrap();
+        assert_eq!(class, Some(1));
+
+        let mut context = FakeContext {
+            output: vec![1.0, 3.0, 0.6],
+            ..FakeContext::default()
+        };
+        assert!(post_process(&mut context, "image.png", &labels, &options).is_err());
+    }
+
+    #[test]
+    fn half_transparent_black_over_white_is_mid_gray() {
+        let mut image = ImageBuffer::from_pixel(1, 1, Rgba([0u8, 0, 0, 128]));
+        composite_background(&mut image, cli::Background::White);
+        let Rgba([red, green, blue, alpha]) = image.get_pixel(0, 0);
+        for channel in [red, green, blue] {
+            assert!(channel.abs_diff(127) <= 1, "{}", channel);
+        }
+        assert_eq!(*alpha, 255);
+
+        let mut image = ImageBuffer::from_pixel(1, 1, Rgba([200u8, 100, 50, 0]));
+        composite_background(&mut image, cli::Background::Black);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn prediction_below_the_threshold_is_unknown() {
+        let labels: HashMap<usize, String> = HashMap::new();
+        let mut options = small_input_options();
+        // the softmax of these logits peaks at about 0.36
+        let output: Vec<f32> = vec![0.0, 0.1, 0.0];
+
+        options.threshold = Some(0.9);
+        let mut context = FakeContext {
+            output: output.clone(),
+            ..FakeContext::default()
+        };
+        let class = post_process(&mut context, "image.png", &labels, &options).unwrap();
+        assert_eq!(class, None);
+
+        options.threshold = Some(0.3);
+        let mut context = FakeContext {
+            output,
+            ..FakeContext::default()
+        };
+        let class = post_process(&mut context, "image.png", &labels, &options).unwrap();
+        assert_eq!(class, Some(1));
+    }
+
+    #[test]
+    fn dropping_the_guard_finishes_the_operation() {
+        let mut tracker = BenchmarkTracker::new();
+        {
+            let guard = tracker.operation("Decode");
+            assert_eq!(guard.current_operations.len(), 1);
+        }
+        assert!(tracker.current_operations.is_empty());
+        assert_eq!(tracker.operation_samples("Decode").len(), 1);
+    }
+
+    #[test]
+    fn the_guard_finishes_the_operation_on_an_early_return() {
+        fn fail(tracker: &mut BenchmarkTracker) -> Result<(), Box<dyn Error>> {
+            let _guard = tracker.operation("Read");
+            Err("read failed".into())
+        }
+
+        let mut tracker = BenchmarkTracker::new();
+        assert!(fail(&mut tracker).is_err());
+        assert!(tracker.current_operations.is_empty());
+        assert_eq!(tracker.completed_metrics[0].name, "Read");
+    }
+
+    #[test]
+    fn nested_operations_finish_inside_out() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.start_phase("Run");
+        tracker.start_operation("A");
+        tracker.start_operation("B");
+        assert_eq!(tracker.current_operations.len(), 2);
+        std::thread::sleep(Duration::from_millis(2));
+        tracker.finish_operation();
+        tracker.finish_operation();
+        tracker.end_phase("Run");
+
+        let names: Vec<&str> = tracker
+            .completed_metrics
+            .iter()
+            .map(|metrics| metrics.name.as_str())
+            .collect();
+        assert_eq!(names, ["B", "A"]);
+        let (b, a) = (&tracker.completed_metrics[0], &tracker.completed_metrics[1]);
+        assert_eq!(b.tags["parent"], "A");
+        assert!(!a.tags.contains_key("parent"));
+        assert!(a.wall_clock_time >= b.wall_clock_time);
+        // only the outer operation counts towards the phase
+        assert_eq!(
+            tracker.phase_metrics[0].1.wall_clock_time,
+            a.wall_clock_time
+        );
+    }
+
+    #[test]
+    fn bottleneck_compares_one_input_with_the_mean_inference() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.completed_metrics = vec![
+            metrics("readimg", 10),
+            metrics("Pre-processing", 20),
+            metrics(FIRST_INFERENCE, 15),
+            metrics("Inference", 5),
+            metrics("Inference", 10),
+        ];
+        let (preprocessing, inference, ratio) = bottleneck(&tracker).unwrap();
+        assert_eq!(preprocessing, Duration::from_millis(30));
+        assert_eq!(inference, Duration::from_millis(10));
+        assert!((ratio - 3.0).abs() < 1e-9);
+        assert_eq!(bottleneck_kind(ratio), "preprocessing-bound");
+
+        tracker.completed_metrics = vec![metrics("readnpy", 2), metrics("Inference", 8)];
+        let (_, _, ratio) = bottleneck(&tracker).unwrap();
+        assert!((ratio - 0.25).abs() < 1e-9);
+        assert_eq!(bottleneck_kind(ratio), "compute-bound");
+
+        tracker.completed_metrics = vec![metrics("readimg", 2)];
+        assert!(bottleneck(&tracker).is_none());
+    }
+
+    #[test]
+    fn a_regression_fails_with_an_annotation() {
+        let dir = test_dir("baseline");
+        let path = dir.join("baseline.bin");
+        let baseline: Vec<Metrics> = (0..10).map(|_| metrics("Inference", 10)).collect();
+        let baseline: Vec<&Metrics> = baseline.iter().collect();
+        fs::write(&path, samples::to_bytes(&baseline, None)).unwrap();
+        let path: &str = path.to_str().unwrap();
+
+        let slower: Vec<Duration> = vec![Duration::from_millis(12); 10];
+        let checks: Vec<BaselineCheck> = check_baseline(path, &slower, 5.0).unwrap();
+        assert!(checks.iter().all(|check| check.regressed));
+        assert!((checks[0].change - 20.0).abs() < 1e-6);
+        let (report, status) = baseline_report(&checks, 5.0, path);
+        assert_eq!(status, 1);
+        assert!(report.contains("Result: FAIL"));
+        assert!(report.contains("::error title=Benchmark regression::median inference"));
+    }
+
+    #[test]
+    fn a_run_within_the_limit_passes() {
+        let dir = test_dir("baseline-pass");
+        let path = dir.join("baseline.bin");
+        let baseline: Vec<Metrics> = (0..10).map(|_| metrics("Inference", 10)).collect();
+        let baseline: Vec<&Metrics> = baseline.iter().collect();
+        fs::write(&path, samples::to_bytes(&baseline, None)).unwrap();
+        let path: &str = path.to_str().unwrap();
+
+        let current: Vec<Duration> = vec![Duration::from_micros(10_200); 10];
+        let checks: Vec<BaselineCheck> = check_baseline(path, &current, 2.5).unwrap();
+        assert!(checks.iter().all(|check| !check.regressed));
+        let (report, status) = baseline_report(&checks, 2.5, path);
+        assert_eq!(status, 0);
+        assert!(report.contains("Result: PASS"));
+        assert!(report.contains("::notice title=Benchmark::no regression over 2.5% against"));
+        assert!(!report.contains("::error"));
+    }
+
+    #[test]
+    fn a_shrinking_peak_rss_does_not_underflow() {
+        let mut start: Metrics = metrics("Inference", 0);
+        start.max_rss = 300 << 20;
+        let mut end: Metrics = metrics("Inference", 0);
+        end.max_rss = 200 << 20;
+        end.timestamp = start.timestamp + Duration::from_millis(1);
+
+        let diff: Metrics = end.diff(&start);
+        assert_eq!(diff.max_rss, 200 << 20);
+
+        let mut later: Metrics = metrics("Inference", 1);
+        later.max_rss = 100 << 20;
+        assert_eq!(diff.combine(&later).max_rss, 200 << 20);
+        assert_eq!(later.combine(&diff).max_rss, 200 << 20);
+    }
+
+    #[test]
+    fn inference_statistics_of_synthetic_samples() {
+        let samples: Vec<Metrics> = [10, 20, 30]
+            .iter()
+            .map(|ms| {
+                let mut sample: Metrics = metrics("Inference", *ms);
+                sample.cpu_usage = *ms as f32;
+                sample
+            })
+            .collect();
+        let times: Vec<Duration> = samples
+            .iter()
+            .map(|sample| sample.wall_clock_time)
+            .collect();
+        let cpu_usages: Vec<f64> = samples
+            .iter()
+            .map(|sample| sample.cpu_usage as f64)
+            .collect();
+
+        assert_eq!(stats::mean(&times), Some(Duration::from_millis(20)));
+        let stddev: Duration = stats::stddev(&times).unwrap();
+        assert!(stddev.abs_diff(Duration::from_millis(10)) < Duration::from_nanos(10));
+        assert_eq!(stats::mean_f64(&cpu_usages), Some(20.0));
+        assert!((stats::stddev_f64(&cpu_usages).unwrap() - 10.0).abs() < 1e-9);
+
+        assert_eq!(stats::mean(&[]), None);
+        assert_eq!(stats::mean_f64(&[]), None);
+        assert_eq!(stats::stddev(&times[..1]), None);
+        // an empty summary must not divide by the zero sample count
+        print_inference_summary(&[]);
+    }
+
+    /// A blue image with a red band of `band` pixels across its middle, vertical when the image
+    /// is wider than tall.
+    fn banded_image(width: u32, height: u32, band: u32) -> image::DynamicImage {
+        let image = ImageBuffer::from_fn(width, height, |x, y| {
+            let (position, length) = if width > height {
+                (x, width)
+            } else {
+                (y, height)
+            };
+            if position.abs_diff(length / 2) < band / 2 {
+                Rgba([255u8, 0, 0, 255])
+            } else {
+                Rgba([0u8, 0, 255, 255])
+            }
+        });
+        image::DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn center_crop_keeps_the_middle_of_any_aspect_ratio() {
+        let preprocess = cli::PreprocessConfig {
+            size: (224, 224),
+            resize: cli::ResizeMode::ResizeShortestThenCenterCrop { resize_to: 256 },
+            ..cli::PreprocessConfig::default()
+        };
+        let red = Rgba([255u8, 0, 0, 255]);
+
+        // a panorama and a tall image are cropped to their red middle
+        for (width, height) in [(2000, 100), (100, 2000)] {
+            let resized = resize_image(&banded_image(width, height, 200), &preprocess);
+            assert_eq!(resized.dimensions(), (224, 224));
+            assert_eq!(resized.get_pixel(0, 0), &red, "{}x{}", width, height);
+            assert_eq!(resized.get_pixel(223, 223), &red, "{}x{}", width, height);
+        }
+
+        // an image smaller than the crop is scaled up
+        let small = image::DynamicImage::ImageRgba8(ImageBuffer::from_pixel(50, 40, red));
+        let resized = resize_image(&small, &preprocess);
+        assert_eq!(resized.dimensions(), (224, 224));
+        assert_eq!(resized.get_pixel(112, 112), &red);
+
+        // a square image keeps the center 224 of its 256 resized pixels
+        let square = resize_image(&banded_image(512, 511, 400), &preprocess);
+        assert_eq!(square.dimensions(), (224, 224));
+    }
+
+    #[test]
+    fn combined_cpu_usage_is_recomputed_from_the_summed_times() {
+        let mut short: Metrics = metrics("Phase", 10);
+        short.user_time = Duration::from_millis(4);
+        short.system_time = Duration::from_millis(1);
+        short.cpu_usage = 50.0;
+        let mut long: Metrics = metrics("Phase", 30);
+        long.user_time = Duration::from_millis(3);
+        long.cpu_usage = 10.0;
+
+        let combined: Metrics = short.combine(&long);
+        let expected: f32 = Metrics::cpu_usage(
+            combined.wall_clock_time,
+            combined.user_time,
+            combined.system_time,
+        );
+        assert_eq!(combined.cpu_usage, expected);
+        // 8 ms of CPU time in 40 ms, not the 30% mean of the two usages
+        assert!((combined.cpu_usage - 20.0).abs() < 1e-4);
+    }
+
+    /// The address and capacity of the buffers after each of 10 items run through them.
+    fn buffer_allocations(reuse: bool) -> Vec<[(usize, usize); 2]> {
+        let options = small_input_options();
+        let mut context = FakeContext {
+            output: vec![0.5; 10],
+            ..FakeContext::default()
+        };
+        let mut buffers = PipelineBuffers::default();
+        (0..10)
+            .map(|_| {
+                buffers.reset(reuse);
+                let image = ImageBuffer::from_pixel(4, 4, Rgba([10u8, 20, 30, 255]));
+                process_image_into(image, &options, &mut buffers.input).unwrap();
+                top_prediction(&mut context, &options, &mut buffers.output).unwrap();
+                [
+                    (buffers.input.as_ptr() as usize, buffers.input.capacity()),
+                    (buffers.output.as_ptr() as usize, buffers.output.capacity()),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reused_buffers_are_not_reallocated() {
+        let allocations = buffer_allocations(true);
+        assert!(allocations.iter().all(|item| *item == allocations[0]));
+        assert!(allocations[0][0].1 >= 4 * 4 * 3 * 4);
+    }
+
+    #[test]
+    fn buffers_without_reuse_are_allocated_per_item() {
+        let mut buffers = PipelineBuffers {
+            input: vec![0; 64],
+            output: vec![0.0; 64],
+        };
+        buffers.reset(false);
+        assert_eq!(
+            (buffers.input.capacity(), buffers.output.capacity()),
+            (0, 0)
+        );
+        assert_eq!(buffer_allocations(false).len(), 10);
+    }
+
+    #[test]
+    fn a_nan_output_is_fatal_under_strict() {
+        let labels: HashMap<usize, String> = HashMap::new();
+        let mut options = small_input_options();
+        let output: Vec<f32> = vec![0.1, f32::NAN, 0.3];
+
+        let mut context = FakeContext {
+            output: output.clone(),
+            ..FakeContext::default()
+        };
+        assert!(post_process(&mut context, "image.png", &labels, &options).is_ok());
+
+        options.strict = true;
+        let mut context = FakeContext {
+            output,
+            ..FakeContext::default()
+        };
+        let error = post_process(&mut context, "image.png", &labels, &options).unwrap_err();
+        assert!(error.to_string().contains("NaN"), "{}", error);
+    }
+
+    #[test]
+    fn calibrated_overhead_is_taken_off_the_raw_time() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.calibrate_overhead(100);
+        // the calibrated overhead may be tiny, so a known one is used for the operation
+        tracker.overhead = Duration::from_millis(1);
+
+        let start: Instant = Instant::now();
+        tracker.start_operation("Inference");
+        std::thread::sleep(Duration::from_millis(3));
+        tracker.finish_operation();
+        let outer: Duration = start.elapsed();
+
+        let corrected: &Metrics = &tracker.completed_metrics[0];
+        let raw: Duration = corrected.wall_clock_time + corrected.overhead;
+        assert_eq!(corrected.overhead, Duration::from_millis(1));
+        assert!(corrected.wall_clock_time <= raw - tracker.overhead);
+        assert!(raw <= outer);
+
+        // an operation shorter than the overhead is corrected to zero, not below
+        tracker.overhead = Duration::from_secs(60);
+        tracker.start_operation("Tiny");
+        tracker.finish_operation();
+        let tiny: &Metrics = &tracker.completed_metrics[1];
+        assert_eq!(tiny.wall_clock_time, Duration::ZERO);
+        assert!(tiny.overhead < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn start_offsets_increase_in_the_text_output() {
+        let mut tracker = BenchmarkTracker::new();
+        tracker.show_start_offsets = true;
+        for name in ["readimg", "Pre-processing", "Inference"] {
+            std::thread::sleep(Duration::from_millis(2));
+            tracker.start_operation(name);
+            tracker.finish_operation();
+        }
+
+        let text: String = tracker
+            .completed_metrics
+            .iter()
+            .map(|metrics| tracker.operation_text(metrics))
+            .collect();
+        // the offsets are printed as `Debug` durations such as `2.1ms` or `350µs`
+        let offsets: Vec<f64> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("Start offset: +"))
+            .map(|offset| {
+                let unit: usize = offset.find(|c: char| c.is_alphabetic()).unwrap();
+                let value: f64 = offset[..unit].parse().unwrap();
+                value
+                    * match &offset[unit..] {
+                        "s" => 1e9,
+                        "ms" => 1e6,
+                        "µs" => 1e3,
+                        _ => 1.0,
+                    }
+            })
+            .collect();
+        assert_eq!(offsets.len(), 3);
+        assert!(
+            offsets.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            offsets
+        );
+    }
+
+    #[test]
+    fn synthetic_tensor_fills_the_shape() {
+        let shape: Vec<usize> = vec![1, 3, 4, 5];
+        let tensor = synthetic_tensor(cli::InputDtype::F32, shape.clone());
+        assert_eq!(tensor.data.len(), 60 * 4);
+        assert_eq!(tensor.shape, shape);
+        assert!(tensor_values(&tensor.data)
+            .iter()
+            .all(|value| *value == 0.5));
+
+        assert_eq!(
+            synthetic_tensor(cli::InputDtype::F16, shape.clone())
+                .data
+                .len(),
+            60 * 2
+        );
+        let tensor = synthetic_tensor(cli::InputDtype::U8, shape);
+        assert_eq!(tensor.data, vec![128; 60]);
+    }
+
+    #[test]
+    fn model_format_prefers_the_option_over_the_extension() {
+        assert!(matches!(
+            resolve_model_format("model.bin", Some(cli::ModelFormat::Onnx)),
+            Ok(cli::ModelFormat::Onnx)
+        ));
+        assert!(matches!(
+            resolve_model_format("model.onnx", None),
+            Ok(cli::ModelFormat::Onnx)
+        ));
+        assert!(matches!(
+            resolve_model_format("model.xml", None),
+            Ok(cli::ModelFormat::OpenVino)
+        ));
+        assert!(resolve_model_format("model", None).is_err());
+    }
 }
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fs;
+
+use crate::cli::InputDtype;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// A little-endian, C-ordered array read from a NumPy `.npy` file.
+#[derive(Debug)]
+pub struct NpyArray {
+    pub dtype: InputDtype,
+    pub shape: Vec<usize>,
+    /// The raw element bytes, exactly as they are passed to `set_input`.
+    pub data: Vec<u8>,
+}
+
+impl NpyArray {
+    /// The elements converted to f32, for comparing against model outputs.
+    pub fn to_f32(&self) -> Vec<f32> {
+        match self.dtype {
+            InputDtype::F32 => self
+                .data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+            InputDtype::F16 => self
+                .data
+                .chunks_exact(2)
+                .map(|bytes| half::f16::from_le_bytes(bytes.try_into().unwrap()).to_f32())
+                .collect(),
+            InputDtype::U8 => self.data.iter().map(|byte| *byte as f32).collect(),
+        }
+    }
+}
+
+pub fn read_npy(path: &str) -> Result<NpyArray, Box<dyn Error>> {
+    parse_npy(&fs::read(path)?).map_err(|error| format!("{}: {}", path, error).into())
+}
+
+/// Parses the `.npy` format: the magic string, a version, the header length and a header that
+/// is a Python dict literal such as
+/// `{'descr': '<f4', 'fortran_order': False, 'shape': (1, 3, 224, 224), }`.
+pub fn parse_npy(bytes: &[u8]) -> Result<NpyArray, Box<dyn Error>> {
+    if bytes.len() < 10 || !bytes.starts_with(NPY_MAGIC) {
+        return Err("Not a .npy file".into());
+    }
+
+    // version 1.0 stores the header length in two bytes, later versions in four
+    let (header_len, header_start): (usize, usize) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        version => return Err(format!("Unsupported .npy version {}", version).into()),
+    };
+    let data_start: usize = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err("Truncated .npy header".into());
+    }
+    let header: &str = std::str::from_utf8(&bytes[header_start..data_start])?;
+
+    let dtype: InputDtype = match header_value(header, "descr")?.trim_matches('\'') {
+        "<f4" => InputDtype::F32,
+        "<f2" => InputDtype::F16,
+        "|u1" => InputDtype::U8,
+        descr => return Err(format!("Unsupported .npy dtype {}", descr).into()),
+    };
+    if header_value(header, "fortran_order")? != "False" {
+        return Err("Fortran-ordered .npy arrays are not supported".into());
+    }
+    let shape: Vec<usize> = header_value(header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse::<usize>())
+        .collect::<Result<_, _>>()?;
+
+    let data: Vec<u8> = bytes[data_start..].to_vec();
+    let expected_len: usize = shape.iter().product::<usize>() * dtype.byte_width();
+    if data.len() != expected_len {
+        return Err(format!(
+            "Shape {:?} needs {} bytes of data but the file has {}",
+            shape,
+            expected_len,
+            data.len()
+        )
+        .into());
+    }
+
+    Ok(NpyArray { dtype, shape, data })
+}
+
+/// Returns the raw value of `key` in the header dict, e.g. `'<f4'` or `(1, 1000)`.
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str, Box<dyn Error>> {
+    let pattern: String = format!("'{}':", key);
+    let start: usize = header
+        .find(&pattern)
+        .ok_or_else(|| format!("Missing '{}' in .npy header", key))?
+        + pattern.len();
+    let value: &str = header[start..].trim_start();
+
+    // the shape is a tuple, which contains commas itself
+    let end: usize = if value.starts_with('(') {
+        value.find(')').map(|end| end + 1)
+    } else {
+        value.find([',', '}'])
+    }
+    .ok_or_else(|| format!("Malformed '{}' in .npy header", key))?;
+
+    Ok(value[..end].trim())
+}
+
+/// A version 1.0 `.npy` file, with the header padded like NumPy pads it.
+#[cfg(test)]
+pub fn npy_bytes(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+    let mut header: String = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    while !(NPY_MAGIC.len() + 4 + header.len() + 1).is_multiple_of(64) {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut bytes: Vec<u8> = NPY_MAGIC.to_vec();
+    bytes.extend([1, 0]);
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float32_array_is_parsed() {
+        let values: [f32; 6] = [0.0, 1.5, -2.0, 3.25, 4.0, -0.5];
+        let data: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+
+        let array: NpyArray = parse_npy(&npy_bytes("<f4", "(2, 3)", &data)).unwrap();
+        assert_eq!(array.dtype, InputDtype::F32);
+        assert_eq!(array.shape, vec![2, 3]);
+        assert_eq!(array.to_f32(), values.to_vec());
+    }
+
+    #[test]
+    fn one_dimensional_shape_has_a_trailing_comma() {
+        let array: NpyArray = parse_npy(&npy_bytes("|u1", "(3,)", &[1, 2, 3])).unwrap();
+        assert_eq!(array.shape, vec![3]);
+        assert_eq!(array.to_f32(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn data_must_match_the_shape() {
+        let error = parse_npy(&npy_bytes("<f4", "(2, 3)", &[0; 20])).unwrap_err();
+        assert!(error.to_string().contains("needs 24 bytes"), "{}", error);
+        assert!(parse_npy(&npy_bytes("<f8", "(1,)", &[0; 8])).is_err());
+        assert!(parse_npy(b"not a npy file").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Just enough of the ONNX protobuf format to read the shape of the model input, which
+//! wasi-nn doesn't report.
+
+use std::error::Error;
+
+/// A protobuf field value. Fixed-width values are skipped, as no field read here uses them.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// field numbers from onnx.proto
+const MODEL_GRAPH: u64 = 7;
+const GRAPH_INITIALIZER: u64 = 5;
+const GRAPH_INPUT: u64 = 11;
+const TENSOR_NAME: u64 = 8;
+const VALUE_INFO_NAME: u64 = 1;
+const VALUE_INFO_TYPE: u64 = 2;
+const TYPE_TENSOR_TYPE: u64 = 1;
+const TENSOR_TYPE_SHAPE: u64 = 2;
+const SHAPE_DIM: u64 = 1;
+const DIM_VALUE: u64 = 1;
+
+/// The dimensions of the first graph input that isn't an initializer, with `None` for symbolic
+/// dimensions such as the batch size. Returns `None` if the input has no shape.
+pub fn input_shape(model: &[u8]) -> Result<Option<Vec<Option<usize>>>, Box<dyn Error>> {
+    let model = fields(model)?;
+    let graph = fields(bytes_field(&model, MODEL_GRAPH).ok_or("The model has no graph")?)?;
+
+    // models before IR version 4 also list the weights as graph inputs
+    let initializers: Vec<&[u8]> = bytes_fields(&graph, GRAPH_INITIALIZER)
+        .into_iter()
+        .map(|initializer| Ok(bytes_field(&fields(initializer)?, TENSOR_NAME)))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    for input in bytes_fields(&graph, GRAPH_INPUT) {
+        let input = fields(input)?;
+        let name: &[u8] = bytes_field(&input, VALUE_INFO_NAME).unwrap_or_default();
+        if initializers.contains(&name) {
+            continue;
+        }
+
+        let shape: Option<&[u8]> = bytes_field(&input, VALUE_INFO_TYPE)
+            .map(fields)
+            .transpose()?
+            .and_then(|input_type| bytes_field(&input_type, TYPE_TENSOR_TYPE))
+            .map(fields)
+            .transpose()?
+            .and_then(|tensor_type| bytes_field(&tensor_type, TENSOR_TYPE_SHAPE));
+        let Some(shape) = shape else {
+            return Ok(None);
+        };
+
+        let dims: Vec<Option<usize>> = bytes_fields(&fields(shape)?, SHAPE_DIM)
+            .into_iter()
+            .map(|dim| {
+                Ok(fields(dim)?.iter().find_map(|(number, value)| match value {
+                    Value::Varint(dim_value) if *number == DIM_VALUE => Some(*dim_value as usize),
+                    _ => None,
+                }))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        return Ok(Some(dims));
+    }
+
+    Ok(None)
+}
+
+/// Checks that the configured input shape has as many elements as the model input, taking
+/// symbolic dimensions of the model from the configured shape.
+pub fn check_input_shape(
+    expected: &[Option<usize>],
+    configured: &[usize],
+) -> Result<(), Box<dyn Error>> {
+    let expected_dims: Vec<String> = expected
+        .iter()
+        .map(|dim| dim.map_or("?".to_string(), |dim| dim.to_string()))
+        .collect();
+    if expected.len() != configured.len() {
+        return Err(format!(
+            "The model expects an input of shape [{}] but the configured shape is {:?}",
+            expected_dims.join(", "),
+            configured
+        )
+        .into());
+    }
+
+    let expected_count: usize = expected
+        .iter()
+        .zip(configured)
+        .map(|(expected, configured)| expected.unwrap_or(*configured))
+        .product();
+    let configured_count: usize = configured.iter().product();
+    if expected_count != configured_count {
+        return Err(format!(
+            "The model expects {} input elements (shape [{}]) but the configured shape {:?} has {}",
+            expected_count,
+            expected_dims.join(", "),
+            configured,
+            configured_count
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The values of every occurrence of a length-delimited field.
+fn bytes_fields<'a>(fields: &[(u64, Value<'a>)], number: u64) -> Vec<&'a [u8]> {
+    fields
+        .iter()
+        .filter_map(|(field, value)| match value {
+            Value::Bytes(bytes) if *field == number => Some(*bytes),
+            _ => None,
+        })
+        .collect()
+}
+
+fn bytes_field<'a>(fields: &[(u64, Value<'a>)], number: u64) -> Option<&'a [u8]> {
+    bytes_fields(fields, number).into_iter().next()
+}
+
+/// Splits a protobuf message into its field numbers and values.
+fn fields(message: &[u8]) -> Result<Vec<(u64, Value<'_>)>, Box<dyn Error>> {
+    let mut fields: Vec<(u64, Value<'_>)> = Vec::new();
+    let mut position: usize = 0;
+
+    while position < message.len() {
+        let key: u64 = read_varint(message, &mut position)?;
+        let value: Value<'_> = match key & 0x7 {
+            0 => Value::Varint(read_varint(message, &mut position)?),
+            1 => {
+                position += 8;
+                Value::Fixed
+            }
+            2 => {
+                let len: usize = read_varint(message, &mut position)? as usize;
+                let bytes: &[u8] = position
+                    .checked_add(len)
+                    .and_then(|end| message.get(position..end))
+                    .ok_or("Truncated ONNX model")?;
+                position += len;
+                Value::Bytes(bytes)
+            }
+            5 => {
+                position += 4;
+                Value::Fixed
+            }
+            wire_type => return Err(format!("Unsupported protobuf wire type {}", wire_type).into()),
+        };
+        fields.push((key >> 3, value));
+    }
+
+    Ok(fields)
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, Box<dyn Error>> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte: u8 = *bytes.get(*position).ok_or("Truncated ONNX model")?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Malformed varint in ONNX model".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_shapes_pass() {
+        assert!(
+            check_input_shape(&[Some(1), Some(3), Some(224), Some(224)], &[1, 3, 224, 224]).is_ok()
+        );
+        // a symbolic batch dimension takes the configured one
+        assert!(
+            check_input_shape(&[None, Some(3), Some(224), Some(224)], &[8, 3, 224, 224]).is_ok()
+        );
+    }
+
+    #[test]
+    fn element_count_mismatch_names_both_counts() {
+        let error: String =
+            check_input_shape(&[Some(1), Some(3), Some(224), Some(224)], &[1, 3, 256, 256])
+                .unwrap_err()
+                .to_string();
+        assert!(error.contains(&(3 * 224 * 224).to_string()), "{}", error);
+        assert!(error.contains(&(3 * 256 * 256).to_string()), "{}", error);
+    }
+
+    #[test]
+    fn rank_mismatch_is_an_error() {
+        assert!(
+            check_input_shape(&[Some(1), Some(3), Some(224), Some(224)], &[3, 224, 224]).is_err()
+        );
+    }
+}
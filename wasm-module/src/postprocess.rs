@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::ops::RangeFrom;
+
+/// Returns the logits of a classification output. The output buffer is always flat; when its
+/// `shape` is known, every singleton dimension is squeezed (so `(1, 1000)` and `(1, 1000, 1, 1)`
+/// both become `(1000)`) and exactly one dimension, the logits, must remain. This keeps the
+/// class index equal to the position along the logits dimension.
+pub fn squeeze_logits<'a>(
+    output: &'a [f32],
+    shape: Option<&[usize]>,
+) -> Result<&'a [f32], Box<dyn Error>> {
+    let Some(shape) = shape else {
+        return Ok(output);
+    };
+
+    let elements: usize = shape.iter().product();
+    if elements != output.len() {
+        return Err(format!(
+            "Output shape {:?} has {} elements but the model returned {}",
+            shape,
+            elements,
+            output.len()
+        )
+        .into());
+    }
+
+    let squeezed: Vec<usize> = shape.iter().cloned().filter(|dim| *dim != 1).collect();
+    if squeezed.len() > 1 {
+        return Err(format!(
+            "Output shape {:?} has more than one non-singleton dimension",
+            shape
+        )
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// The class and score of the highest logit. Classes are numbered from 0, like the lines of a
+/// labels file.
+pub fn argmax(logits: &[f32]) -> Option<(i32, f32)> {
+    logits
+        .iter()
+        .cloned()
+        .zip(RangeFrom::<i32> { start: 0 })
+        .max_by(|(score1, _), (score2, _)| score1.partial_cmp(score2).unwrap_or(Ordering::Equal))
+        .map(|(score, class)| (class, score))
+}
+
+/// Like `argmax`, but every score within `tolerance` of the highest one counts as a tie, and a
+/// tie goes to the lowest class, so that floating-point noise can't change the prediction.
+pub fn argmax_with_tolerance(logits: &[f32], tolerance: f32) -> Option<(i32, f32)> {
+    let (_, top_score) = argmax(logits)?;
+    logits
+        .iter()
+        .cloned()
+        .zip(RangeFrom::<i32> { start: 0 })
+        .find(|(score, _)| *score >= top_score - tolerance)
+        .map(|(score, class)| (class, score))
+}
+
+/// Checks that every repeat predicted the same class as the first one, with a top score
+/// within `tolerance` of the first one's.
+pub fn check_stable(predictions: &[(i32, f32)], tolerance: f32) -> Result<(), Box<dyn Error>> {
+    let Some(&(class, score)) = predictions.first() else {
+        return Ok(());
+    };
+
+    for (repeat, &(repeat_class, repeat_score)) in predictions.iter().enumerate().skip(1) {
+        if repeat_class != class || (repeat_score - score).abs() > tolerance {
+            return Err(format!(
+                "Unstable prediction: repeat 1 predicted class {} (score: {}) but repeat {} predicted class {} (score: {})",
+                class,
+                score,
+                repeat + 1,
+                repeat_class,
+                repeat_score
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads ground truth labels, one `<image file name> <class>` pair per line, with the classes
+/// numbered like the predictions. Empty lines and lines starting with `#` are skipped.
+pub fn read_ground_truth(path: &str) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.rsplit_once(char::is_whitespace) {
+            Some((image, class)) => match class.parse::<i32>() {
+                Ok(class) => Ok((image.trim().to_string(), class)),
+                Err(_) => Err(format!("Invalid class in ground truth line '{}'", line).into()),
+            },
+            None => Err(format!("Invalid ground truth line '{}'", line).into()),
+        })
+        .collect()
+}
+
+/// Largest element-wise differences between a model output and a reference output.
+#[derive(Debug)]
+pub struct Comparison {
+    pub max_abs_error: f32,
+    pub max_rel_error: f32,
+}
+
+pub fn compare_output(output: &[f32], reference: &[f32]) -> Result<Comparison, Box<dyn Error>> {
+    if output.len() != reference.len() {
+        return Err(format!(
+            "The output has {} elements but the reference has {}",
+            output.len(),
+            reference.len()
+        )
+        .into());
+    }
+
+    let mut comparison = Comparison {
+        max_abs_error: 0.0,
+        max_rel_error: 0.0,
+    };
+    for (value, expected) in output.iter().zip(reference) {
+        let abs_error: f32 = (value - expected).abs();
+        comparison.max_abs_error = comparison.max_abs_error.max(abs_error);
+        comparison.max_rel_error = comparison
+            .max_rel_error
+            .max(abs_error / expected.abs().max(f32::EPSILON));
+    }
+
+    Ok(comparison)
+}
+
+/// Numerically stable softmax: the largest logit is subtracted before exponentiating so that
+/// large logits don't overflow to infinity.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max: f32 = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// The `k` most probable classes with their softmax probabilities, most probable first. Classes
+/// are numbered from 0 like in `argmax`.
+pub fn top_k(logits: &[f32], k: usize) -> Vec<(i32, f32)> {
+    let mut probabilities: Vec<(i32, f32)> = softmax(logits)
+        .into_iter()
+        .zip(RangeFrom::<i32> { start: 0 })
+        .map(|(probability, class)| (class, probability))
+        .collect();
+    // a stable sort keeps tied classes in index order
+    probabilities.sort_by(|(_, p1), (_, p2)| p2.partial_cmp(p1).unwrap_or(Ordering::Equal));
+    probabilities.truncate(k);
+    probabilities
+}
+
+/// The largest softmax probability, i.e. the confidence of the top class.
+pub fn top_probability(logits: &[f32]) -> f32 {
+    softmax(logits).into_iter().fold(0.0, f32::max)
+}
+
+/// The difference between the two largest probabilities, `None` for fewer than two classes. A
+/// small margin marks a borderline prediction even when the top probability looks high.
+pub fn confidence_margin(probabilities: &[f32]) -> Option<f32> {
+    let mut top: [f32; 2] = [f32::NEG_INFINITY; 2];
+    for probability in probabilities {
+        if *probability > top[0] {
+            top = [*probability, top[0]];
+        } else if *probability > top[1] {
+            top[1] = *probability;
+        }
+    }
+    (probabilities.len() >= 2).then(|| top[0] - top[1])
+}
+
+/// Shannon entropy of a probability distribution in nats.
+pub fn entropy(probabilities: &[f32]) -> f32 {
+    -probabilities
+        .iter()
+        .filter(|probability| **probability > 0.0)
+        .map(|probability| probability * probability.ln())
+        .sum::<f32>()
+}
+
+/// Prints the sum, entropy and top-2 confidence margin of the softmax distribution over
+/// `logits`. A sum far from 1.0 points at a decoding or shape bug, a high entropy or a small
+/// margin at an uncertain model. Returns whether the sum is 1.0 within the tolerance.
+pub fn print_softmax_diagnostics(logits: &[f32]) -> bool {
+    const SUM_TOLERANCE: f32 = 1e-3;
+
+    let probabilities: Vec<f32> = softmax(logits);
+    let sum: f32 = probabilities.iter().sum();
+    let entropy: f32 = entropy(&probabilities);
+    // entropy of the uniform distribution, the upper bound
+    let max_entropy: f32 = (probabilities.len() as f32).ln();
+
+    reportln!(
+        "Softmax sum: {} (entropy: {} nats, max {} nats)",
+        sum,
+        entropy,
+        max_entropy
+    );
+    if let Some(margin) = confidence_margin(&probabilities) {
+        reportln!("Confidence margin (top-1 - top-2): {}", margin);
+    }
+    (sum - 1.0).abs() <= SUM_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-6;
+
+    #[test]
+    fn softmax_sums_to_one_and_keeps_the_order() {
+        let probabilities: Vec<f32> = softmax(&[1.0, 2.0, 3.0]);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < EPSILON);
+        assert!(probabilities[0] < probabilities[1] && probabilities[1] < probabilities[2]);
+        // e^-2 : e^-1 : 1
+        assert!((probabilities[2] - 0.665_240_9).abs() < EPSILON);
+    }
+
+    #[test]
+    fn softmax_does_not_overflow_on_large_logits() {
+        let probabilities: Vec<f32> = softmax(&[1000.0, 1000.0]);
+        assert_eq!(probabilities, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn top_k_orders_by_probability() {
+        let top: Vec<(i32, f32)> = top_k(&[0.5, 3.0, -1.0, 2.0], 2);
+        let classes: Vec<i32> = top.iter().map(|(class, _)| *class).collect();
+        assert_eq!(classes, vec![1, 3]);
+        assert!(top[0].1 > top[1].1);
+        assert_eq!(top[0].1, top_probability(&[0.5, 3.0, -1.0, 2.0]));
+    }
+
+    #[test]
+    fn top_k_keeps_tied_classes_in_index_order() {
+        let top: Vec<(i32, f32)> = top_k(&[1.0, 2.0, 2.0, 2.0], 3);
+        let classes: Vec<i32> = top.iter().map(|(class, _)| *class).collect();
+        assert_eq!(classes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn top_k_is_limited_by_the_class_count() {
+        assert_eq!(top_k(&[1.0, 2.0], 5).len(), 2);
+        assert!(top_k(&[1.0, 2.0], 0).is_empty());
+        assert!(top_k(&[], 5).is_empty());
+    }
+
+    #[test]
+    fn identical_predictions_are_stable() {
+        assert!(check_stable(&[(207, 12.5); 5], 1e-4).is_ok());
+        assert!(check_stable(&[], 1e-4).is_ok());
+    }
+
+    #[test]
+    fn score_variation_within_the_tolerance_is_stable() {
+        assert!(check_stable(&[(207, 12.5), (207, 12.500_05), (207, 12.499_95)], 1e-3).is_ok());
+    }
+
+    #[test]
+    fn injected_variation_is_unstable() {
+        let error = check_stable(&[(207, 12.5), (207, 12.5), (207, 12.6)], 1e-3).unwrap_err();
+        assert!(error.to_string().contains("repeat 3"), "{}", error);
+        assert!(check_stable(&[(207, 12.5), (208, 12.5)], 1e-3).is_err());
+    }
+
+    #[test]
+    fn uniform_entropy_is_the_log_of_the_class_count() {
+        assert!((entropy(&[0.25; 4]) - 4f32.ln()).abs() < EPSILON);
+        assert_eq!(entropy(&[1.0, 0.0, 0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn singleton_dimensions_do_not_change_the_class() {
+        let mut output: Vec<f32> = vec![0.0; 1000];
+        output[207] = 5.0;
+
+        let flat: &[f32] = squeeze_logits(&output, Some(&[1, 1000])).unwrap();
+        let spatial: &[f32] = squeeze_logits(&output, Some(&[1, 1000, 1, 1])).unwrap();
+        assert_eq!(argmax(flat), Some((207, 5.0)));
+        assert_eq!(argmax(spatial), argmax(flat));
+    }
+
+    #[test]
+    fn shapes_with_several_dimensions_are_rejected() {
+        assert!(squeeze_logits(&[0.0; 6], Some(&[1, 2, 3])).is_err());
+        assert!(squeeze_logits(&[0.0; 6], Some(&[1, 1000])).is_err());
+    }
+
+    #[test]
+    fn output_within_the_tolerance_matches_the_reference() {
+        let reference: [f32; 3] = [1.0, -2.0, 0.5];
+        let comparison: Comparison = compare_output(&[1.000_01, -2.0, 0.5], &reference).unwrap();
+        assert!(comparison.max_abs_error < 1e-4);
+        assert!(comparison.max_rel_error < 1e-4);
+    }
+
+    #[test]
+    fn divergent_output_is_reported() {
+        let reference: [f32; 3] = [1.0, -2.0, 0.5];
+        let comparison: Comparison = compare_output(&[1.0, -2.0, 1.0], &reference).unwrap();
+        assert!((comparison.max_abs_error - 0.5).abs() < EPSILON);
+        assert!((comparison.max_rel_error - 1.0).abs() < EPSILON);
+        assert!(compare_output(&[1.0, -2.0], &reference).is_err());
+    }
+
+    #[test]
+    fn confidence_margin_separates_clear_and_narrow_gaps() {
+        let clear: f32 = confidence_margin(&[0.9, 0.05, 0.05]).unwrap();
+        let narrow: f32 = confidence_margin(&[0.1, 0.46, 0.44]).unwrap();
+        assert!((clear - 0.85).abs() < EPSILON);
+        assert!((narrow - 0.02).abs() < EPSILON);
+        assert!(confidence_margin(&[1.0]).is_none());
+    }
+
+    #[test]
+    fn a_near_tie_goes_to_the_lower_class() {
+        let logits: [f32; 4] = [0.1, 5.0, 0.2, 5.000_001];
+        assert_eq!(argmax(&logits), Some((3, 5.000_001)));
+        assert_eq!(argmax_with_tolerance(&logits, 1e-4), Some((1, 5.0)));
+        assert_eq!(argmax_with_tolerance(&logits, 0.0), Some((3, 5.000_001)));
+        assert_eq!(argmax_with_tolerance(&[], 1e-4), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! A compact binary format for the inference samples, for sweeps too large to export as JSON.
+//!
+//! All integers are little-endian. The file starts with the magic string, a version byte and the
+//! run id as a `u32` length and UTF-8 bytes (empty without a run id), followed by a `u64` sample
+//! count. Each sample is its wall-clock, user and system times in nanoseconds and its max RSS in
+//! bytes as four `u64`s, then a `u16` tag count and each tag as a key and a value, each a `u16`
+//! length and UTF-8 bytes.
+
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+use crate::Metrics;
+
+const MAGIC: &[u8] = b"WMBSAMPL";
+const VERSION: u8 = 1;
+
+/// A sample read back from the binary format.
+#[derive(Debug, PartialEq)]
+pub struct Sample {
+    pub wall_clock_time: Duration,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub max_rss: u64,
+    pub tags: Vec<(String, String)>,
+}
+
+pub fn to_bytes(samples: &[&Metrics], run_id: Option<&str>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = MAGIC.to_vec();
+    bytes.push(VERSION);
+    let run_id: &[u8] = run_id.unwrap_or_default().as_bytes();
+    bytes.extend((run_id.len() as u32).to_le_bytes());
+    bytes.extend(run_id);
+    bytes.extend((samples.len() as u64).to_le_bytes());
+
+    for metrics in samples {
+        for nanos in [
+            metrics.wall_clock_time.as_nanos() as u64,
+            metrics.user_time.as_nanos() as u64,
+            metrics.system_time.as_nanos() as u64,
+            metrics.max_rss,
+        ] {
+            bytes.extend(nanos.to_le_bytes());
+        }
+        bytes.extend((metrics.tags.len() as u16).to_le_bytes());
+        for (key, value) in &metrics.tags {
+            for text in [key, value] {
+                bytes.extend((text.len() as u16).to_le_bytes());
+                bytes.extend(text.as_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+/// Reads a binary samples file, returning the run id and the samples.
+pub fn read(path: &str) -> Result<(Option<String>, Vec<Sample>), Box<dyn Error>> {
+    from_bytes(&fs::read(path)?).map_err(|error| format!("{}: {}", path, error).into())
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<(Option<String>, Vec<Sample>), Box<dyn Error>> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a binary samples file".into());
+    }
+    let version: u8 = reader.take(1)?[0];
+    if version != VERSION {
+        return Err(format!("Unsupported binary samples version {}", version).into());
+    }
+
+    let run_id_len: usize = u32::from_le_bytes(reader.array()?) as usize;
+    let run_id: String = String::from_utf8(reader.take(run_id_len)?.to_vec())?;
+    let count: u64 = u64::from_le_bytes(reader.array()?);
+
+    let mut samples: Vec<Sample> = Vec::new();
+    for _ in 0..count {
+        let wall_clock_time = Duration::from_nanos(u64::from_le_bytes(reader.array()?));
+        let user_time = Duration::from_nanos(u64::from_le_bytes(reader.array()?));
+        let system_time = Duration::from_nanos(u64::from_le_bytes(reader.array()?));
+        let max_rss: u64 = u64::from_le_bytes(reader.array()?);
+
+        let tag_count: u16 = u16::from_le_bytes(reader.array()?);
+        let mut tags: Vec<(String, String)> = Vec::new();
+        for _ in 0..tag_count {
+            tags.push((reader.string()?, reader.string()?));
+        }
+
+        samples.push(Sample {
+            wall_clock_time,
+            user_time,
+            system_time,
+            max_rss,
+            tags,
+        });
+    }
+    if reader.position != bytes.len() {
+        return Err("Trailing bytes after the samples".into());
+    }
+
+    Ok(((!run_id.is_empty()).then_some(run_id), samples))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes: &[u8] = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.position..end))
+            .ok_or("Truncated binary samples file")?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        let len: usize = u16::from_le_bytes(self.array()?) as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    fn metrics(wall_clock_ns: u64, tags: &[(&str, &str)]) -> Metrics {
+        Metrics {
+            name: "Inference".to_string(),
+            timestamp: Instant::now(),
+            wall_clock_time: Duration::from_nanos(wall_clock_ns),
+            user_time: Duration::from_nanos(wall_clock_ns / 2),
+            system_time: Duration::from_nanos(wall_clock_ns / 4),
+            max_rss: 64 << 20,
+            cpu_usage: 0.0,
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            allocations: 0,
+            allocated_bytes: 0,
+            overhead: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let first = metrics(1_500_000, &[("batch_size", "8"), ("image", "dög.jpg")]);
+        let second = metrics(u64::MAX, &[]);
+        let bytes: Vec<u8> = to_bytes(&[&first, &second], Some("build-1"));
+
+        let (run_id, samples) = from_bytes(&bytes).unwrap();
+        assert_eq!(run_id.as_deref(), Some("build-1"));
+        assert_eq!(
+            samples,
+            vec![
+                Sample {
+                    wall_clock_time: Duration::from_nanos(1_500_000),
+                    user_time: Duration::from_nanos(750_000),
+                    system_time: Duration::from_nanos(375_000),
+                    max_rss: 64 << 20,
+                    tags: vec![
+                        ("batch_size".to_string(), "8".to_string()),
+                        ("image".to_string(), "dög.jpg".to_string()),
+                    ],
+                },
+                Sample {
+                    wall_clock_time: Duration::from_nanos(u64::MAX),
+                    user_time: Duration::from_nanos(u64::MAX / 2),
+                    system_time: Duration::from_nanos(u64::MAX / 4),
+                    max_rss: 64 << 20,
+                    tags: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_run_id_reads_back_as_none() {
+        let (run_id, samples) = from_bytes(&to_bytes(&[], None)).unwrap();
+        assert_eq!(run_id, None);
+        assert!(samples.is_empty());
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected() {
+        let bytes: Vec<u8> = to_bytes(&[&metrics(1000, &[("key", "value")])], None);
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(from_bytes(b"NOTSAMPL").is_err());
+
+        let mut bytes: Vec<u8> = bytes;
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(from_bytes(&bytes).is_err());
+    }
+}
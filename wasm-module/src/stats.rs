@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+pub fn mean(durations: &[Duration]) -> Option<Duration> {
+    if durations.is_empty() {
+        return None;
+    }
+    Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+}
+
+/// The geometric mean, the n-th root of the product of the durations, computed from the mean
+/// of their logarithms. `None` if there are no durations or one of them is zero.
+pub fn geometric_mean(durations: &[Duration]) -> Option<Duration> {
+    if durations.is_empty() || durations.iter().any(|duration| duration.is_zero()) {
+        return None;
+    }
+    let mean_ln: f64 = durations
+        .iter()
+        .map(|duration| duration.as_secs_f64().ln())
+        .sum::<f64>()
+        / durations.len() as f64;
+    Some(Duration::from_secs_f64(mean_ln.exp()))
+}
+
+/// The nearest-rank percentile, e.g. `percentile(durations, 99.0)` for the p99.
+pub fn percentile(durations: &[Duration], percentile: f64) -> Option<Duration> {
+    if durations.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<Duration> = durations.to_vec();
+    sorted.sort();
+
+    let rank: usize = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// The middle value of the durations, averaging the two middle values of an even count.
+pub fn median(durations: &[Duration]) -> Option<Duration> {
+    let mut sorted: Vec<Duration> = durations.to_vec();
+    sorted.sort();
+
+    let middle: usize = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[middle - 1] + sorted[middle]) / 2),
+        _ => Some(sorted[middle]),
+    }
+}
+
+/// The sample standard deviation, `None` for fewer than two durations.
+pub fn stddev(durations: &[Duration]) -> Option<Duration> {
+    let seconds: Vec<f64> = durations.iter().map(Duration::as_secs_f64).collect();
+    stddev_f64(&seconds).map(Duration::from_secs_f64)
+}
+
+/// The mean of plain values, such as CPU usages, `None` without values.
+pub fn mean_f64(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// The sample standard deviation of plain values, such as CPU usages.
+pub fn stddev_f64(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+    let mean: f64 = mean_f64(values)?;
+    let variance: f64 = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / (values.len() - 1) as f64;
+    Some(variance.sqrt())
+}
+
+/// Splits the range from the shortest to the longest duration into `buckets` equal buckets and
+/// counts the durations in each. Returns the start of each bucket with its count.
+pub fn histogram(durations: &[Duration], buckets: usize) -> Vec<(Duration, usize)> {
+    let (Some(min), Some(max)) = (durations.iter().min(), durations.iter().max()) else {
+        return Vec::new();
+    };
+    if buckets == 0 {
+        return Vec::new();
+    }
+    let range: Duration = *max - *min;
+
+    let mut counts: Vec<usize> = vec![0; buckets];
+    for duration in durations {
+        // in f64, as a bucket width in whole nanoseconds rounds to 0 for a range of fewer
+        // nanoseconds than buckets
+        let bucket: usize = if range.is_zero() {
+            0
+        } else {
+            ((*duration - *min).as_secs_f64() / range.as_secs_f64() * buckets as f64) as usize
+        };
+        // the longest duration closes the last bucket
+        counts[bucket.min(buckets - 1)] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(bucket, count)| (*min + range.mul_f64(bucket as f64 / buckets as f64), count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bimodal_durations_fill_the_outer_buckets() {
+        let durations: Vec<Duration> = [10, 10, 11, 10, 20, 19, 20, 20]
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect();
+        let histogram: Vec<(Duration, usize)> = histogram(&durations, 4);
+
+        let counts: Vec<usize> = histogram.iter().map(|(_, count)| *count).collect();
+        assert_eq!(counts, vec![4, 0, 0, 4]);
+        assert_eq!(histogram[0].0, Duration::from_millis(10));
+        assert_eq!(histogram[2].0, Duration::from_millis(15));
+    }
+
+    #[test]
+    fn a_range_of_a_few_nanoseconds_is_still_split() {
+        let durations: Vec<Duration> = [0, 0, 3]
+            .iter()
+            .map(|ns| Duration::from_nanos(*ns))
+            .collect();
+        let counts: Vec<usize> = histogram(&durations, 10)
+            .iter()
+            .map(|(_, count)| *count)
+            .collect();
+        assert_eq!(counts[0], 2);
+        assert_eq!(counts[9], 1);
+        assert_eq!(counts.iter().sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn equal_durations_share_the_first_bucket() {
+        let durations: Vec<Duration> = vec![Duration::from_millis(5); 3];
+        assert_eq!(histogram(&durations, 3)[0], (Duration::from_millis(5), 3));
+        assert!(histogram(&durations, 0).is_empty());
+        assert!(histogram(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn geometric_mean_is_the_root_of_the_product() {
+        // (1 ms * 4 ms * 16 ms)^(1/3) = 4 ms
+        let durations: Vec<Duration> = [1, 4, 16]
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect();
+        let mean: Duration = geometric_mean(&durations).unwrap();
+        assert!(mean.abs_diff(Duration::from_millis(4)) < Duration::from_nanos(10));
+        assert!(geometric_mean(&[Duration::ZERO, Duration::from_millis(1)]).is_none());
+        assert!(geometric_mean(&[]).is_none());
+    }
+}
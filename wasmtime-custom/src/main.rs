@@ -4,22 +4,53 @@ extern crate wasi_common;
 extern crate anyhow;
 extern crate cap_std;
 extern crate wasmtime_wasi_nn;
+extern crate libc;
+#[cfg(test)]
+extern crate wat;
 
-use anyhow::{Ok, Result};
-use std::{env, path::Path, time::Instant};
-use wasmtime::{Config, Engine, Module, Store};
-use wasi_common::{sync::Dir, sync::WasiCtxBuilder, WasiCtx};
+use anyhow::{bail, Ok, Result};
+use std::{
+    collections::HashMap,
+    env,
+    path::Path,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+use wasmtime::{Caller, Config, Engine, Extern, InstanceAllocationStrategy, Linker, Module, PoolingAllocationConfig, Store, Trap, WasmBacktrace};
+use wasi_common::{sync::Dir, sync::WasiCtxBuilder, I32Exit, WasiCtx};
 use wasmtime::component::__internal::wasmtime_environ::__core::result::Result::Ok as WasmtimeResultOk;
-use wasmtime_wasi_nn::{InMemoryRegistry, WasiNnCtx, backend::onnxruntime::OnnxBackend};
+use wasmtime_wasi_nn::{Backend, InMemoryRegistry, WasiNnCtx, backend::onnxruntime::OnnxBackend, backend::openvino::OpenvinoBackend, wit::types::ExecutionTarget};
 
+/// Set when the module prints its metrics with `--format json`, so that stdout stays the JSON
+/// object alone.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for the run report, which goes to stderr when the module prints JSON.
+macro_rules! reportln {
+    ($($arg:tt)*) => {
+        if JSON_OUTPUT.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 /// The host state for running wasi-nn tests.
 struct Ctx {
     wasi: WasiCtx,
     wasi_nn: WasiNnCtx,
+    /// Calls of the `benchmark.getrusage` import and the time spent servicing them, which the
+    /// module's measurements include.
+    getrusage_calls: u64,
+    getrusage_time: Duration,
 }
+const RUN_ID_VAR: &str = "BENCHMARK_RUN_ID";
+
 impl Ctx {
-    fn new(directories: &Vec<&str>) -> Result<Self> {
+    fn new(directories: &Vec<&str>, module_args: &[String], host_options: &HostOptions) -> Result<Self> {
         let preopen_dirs = directories
             .iter()
             .map(|dir| {
@@ -27,77 +58,704 @@ impl Ctx {
             }.unwrap());
 
         let mut binding = WasiCtxBuilder::new();
-        let builder = binding.inherit_stdio();
+        let builder = binding.inherit_stdio().args(module_args)?;
+        // the module tags its results with the run id, e.g. a build id set by CI
+        if let WasmtimeResultOk(run_id) = env::var(RUN_ID_VAR) {
+            builder.env(RUN_ID_VAR, &run_id)?;
+        }
         for (preopen_dir, path) in preopen_dirs.zip(directories) {
             builder.preopened_dir(preopen_dir, path)?;
         }
 
         let wasi = builder.build();
+        let onnx_backend = if host_options.single_thread {
+            OnnxBackend::single_threaded()
+        } else {
+            OnnxBackend::default()
+        };
+        let mut backends: Vec<Backend> = vec![onnx_backend.into()];
+        // wasmtime has no TensorFlow Lite backend, so the module fails to load such models
+        if host_options.openvino {
+            backends.push(OpenvinoBackend::default().into());
+        }
         let wasi_nn = WasiNnCtx::new(
-            [OnnxBackend::default().into()],
+            backends,
             InMemoryRegistry::new().into()
         );
 
-        Ok(Self { wasi, wasi_nn })
+        Ok(Self { wasi, wasi_nn, getrusage_calls: 0, getrusage_time: Duration::ZERO })
+    }
+
+    /// The calls of the getrusage shim and the time spent in it, with its share of `host_call`,
+    /// the time of the call into the module.
+    fn getrusage_summary(&self, host_call: Duration) -> String {
+        format!(
+            "getrusage shim: {} calls, {:?}, {:.3}% of the call",
+            self.getrusage_calls,
+            self.getrusage_time,
+            100.0 * self.getrusage_time.as_secs_f64() / host_call.as_secs_f64()
+        )
+    }
+}
+
+/// Resident set size of this process in bytes: the current value from `/proc/self/statm`
+/// (0 where that isn't available) and the peak value from `getrusage`.
+fn rss_bytes() -> (u64, u64) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let current = std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1).and_then(|pages| pages.parse::<u64>().ok()))
+        .map_or(0, |pages| pages * page_size);
+
+    let (_, _, peak) = process_rusage();
+    (current, peak)
+}
+
+/// User time, system time and peak resident set size in bytes of this process, from
+/// `getrusage`.
+fn process_rusage() -> (Duration, Duration, u64) {
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+    let timeval = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    // ru_maxrss is in kilobytes on Linux but in bytes on macOS
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+    (timeval(usage.ru_utime), timeval(usage.ru_stime), max_rss)
+}
+
+/// Links `benchmark.getrusage`, which the module calls to measure its operations, as WASI has
+/// no resource usage. It writes the user and system time of the host process in microseconds
+/// and its peak RSS in bytes, as three little-endian `u64`s, to the given address in the
+/// module's memory, and returns 0. The time spent in the import is added up in the `Ctx`.
+fn add_getrusage_to_linker(linker: &mut Linker<Ctx>) -> Result<()> {
+    linker.func_wrap("benchmark", "getrusage", |mut caller: Caller<'_, Ctx>, address: i32| -> Result<i32> {
+        let start = Instant::now();
+        let memory = match caller.get_export("memory") {
+            Some(Extern::Memory(memory)) => memory,
+            _ => bail!("the module exports no memory"),
+        };
+        let (user_time, system_time, max_rss) = process_rusage();
+        let mut usage = [0u8; 24];
+        usage[0..8].copy_from_slice(&(user_time.as_micros() as u64).to_le_bytes());
+        usage[8..16].copy_from_slice(&(system_time.as_micros() as u64).to_le_bytes());
+        usage[16..24].copy_from_slice(&max_rss.to_le_bytes());
+        memory.write(&mut caller, address as u32 as usize, &usage)?;
+        let ctx = caller.data_mut();
+        ctx.getrusage_calls += 1;
+        ctx.getrusage_time += start.elapsed();
+        Ok(0)
+    })?;
+    Ok(())
+}
+
+/// Options for the host itself, given before the wasm module path.
+#[derive(Debug, Default)]
+struct HostOptions {
+    /// Spawn this many benchmark processes concurrently and aggregate their throughput.
+    processes: Option<usize>,
+    /// Don't write the compiled module next to the wasm file, e.g. on read-only filesystems.
+    no_cache_write: bool,
+    /// Maximum stack space in bytes that wasm code may use, for deep models that overflow the
+    /// default. It has to fit in the native stack of the thread running the module.
+    max_wasm_stack: Option<usize>,
+    /// Create the directories the module expects and exit.
+    init: bool,
+    /// Print the compiled-in wasi-nn backends and exit.
+    list_backends: bool,
+    /// Use the pooling instance allocator instead of allocating every instance on demand.
+    pooling: bool,
+    /// Instances, memories and tables the pooling allocator keeps slots for.
+    pooling_instances: Option<u32>,
+    /// Largest linear memory, in bytes, a pooling allocator slot can hold.
+    pooling_max_memory: Option<usize>,
+    /// Instantiate the module this many times in fresh stores before the run and report the
+    /// mean instantiation time.
+    instantiations: Option<usize>,
+    /// Compare the time measured around the call into the module with the times the module
+    /// measures itself.
+    compare_timing: bool,
+    /// Run the ONNX runtime on one intra-op and one inter-op thread.
+    single_thread: bool,
+    /// Also register the OpenVINO backend, for modules loading `.xml` models. It needs the
+    /// OpenVINO libraries at runtime.
+    openvino: bool,
+    /// Flush denormal floats to zero during the call into the module.
+    flush_denormals: bool,
+    /// Call the module a second time with the opposite denormal setting and compare the times.
+    compare_denormals: bool,
+    /// Print the usage and exit.
+    help: bool,
+    /// Name of a model in the model directory, passed to the module as its path.
+    model: Option<String>,
+    /// Name of an image in the image directory, passed to the module as its path.
+    image: Option<String>,
+}
+
+const USAGE: &str = "\
+Usage: wasmtime-test [host options] <wasm module> [module arguments]
+
+The module arguments are passed on to the module, see its --help.
+
+Host options:
+  --init                      Create the directories shared with the module and exit
+  --list-backends             Print the compiled-in wasi-nn backends and exit
+  --no-cache-write            Don't write the serialized module next to the wasm file
+  --max-wasm-stack BYTES      Maximum wasm stack size
+  --processes N               Run N copies of the benchmark at the same time
+  --pooling                   Use the pooling instance allocator
+  --pooling-instances N       Instance slots of the pooling allocator [default: 16]
+  --pooling-max-memory BYTES  Memory size of a pooling allocator slot [default: 1 GiB]
+  --instantiations N          Measure the mean time of N instantiations
+  --compare-timing            Compare the host and module timings
+  --single-thread             Run the ONNX runtime on a single thread
+  --openvino                  Also register the OpenVINO backend, for .xml models
+  --flush-denormals           Flush denormal floats to zero (x86-64, implies --single-thread)
+  --compare-denormals         Also run with the opposite denormal setting and compare
+  --model NAME                Run the model NAME or NAME.onnx from assets/models
+  --image NAME                Use the image NAME or NAME.<ext> from assets/imgs
+  --help                      Print this help
+";
+
+impl HostOptions {
+    /// Splits the arguments into the host options and the module arguments, which start with
+    /// the wasm module path.
+    fn parse(args: &[String]) -> Result<(Self, &[String])> {
+        let mut options = HostOptions::default();
+        let mut index = 0;
+
+        while index < args.len() && args[index].starts_with("--") {
+            let flag = args[index].as_str();
+            let value = args.get(index + 1);
+            match (flag, value) {
+                ("--no-cache-write", _) => {
+                    options.no_cache_write = true;
+                    index += 1;
+                }
+                ("--init", _) => {
+                    options.init = true;
+                    index += 1;
+                }
+                ("--list-backends", _) => {
+                    options.list_backends = true;
+                    index += 1;
+                }
+                ("--help", _) => {
+                    options.help = true;
+                    index += 1;
+                }
+                ("--compare-timing", _) => {
+                    options.compare_timing = true;
+                    index += 1;
+                }
+                ("--single-thread", _) => {
+                    options.single_thread = true;
+                    index += 1;
+                }
+                ("--openvino", _) => {
+                    options.openvino = true;
+                    index += 1;
+                }
+                ("--flush-denormals", _) => {
+                    options.flush_denormals = true;
+                    index += 1;
+                }
+                ("--compare-denormals", _) => {
+                    options.compare_denormals = true;
+                    index += 1;
+                }
+                ("--pooling", _) => {
+                    options.pooling = true;
+                    index += 1;
+                }
+                ("--pooling-instances", Some(value)) => {
+                    options.pooling_instances = Some(value.parse()?);
+                    index += 2;
+                }
+                ("--pooling-max-memory", Some(value)) => {
+                    options.pooling_max_memory = Some(value.parse()?);
+                    index += 2;
+                }
+                ("--instantiations", Some(value)) => {
+                    options.instantiations = Some(value.parse()?);
+                    index += 2;
+                }
+                ("--model", Some(value)) => {
+                    options.model = Some(value.clone());
+                    index += 2;
+                }
+                ("--image", Some(value)) => {
+                    options.image = Some(value.clone());
+                    index += 2;
+                }
+                ("--processes", Some(value)) => {
+                    options.processes = Some(value.parse()?);
+                    index += 2;
+                }
+                ("--max-wasm-stack", Some(value)) => {
+                    options.max_wasm_stack = Some(value.parse()?);
+                    index += 2;
+                }
+                _ => bail!("Unknown or incomplete host argument: {}", flag),
+            }
+        }
+
+        // the flush-to-zero mode is per thread, and the threads of the ONNX runtime's pool don't
+        // inherit it from the thread calling into the module
+        if options.flush_denormals || options.compare_denormals {
+            options.single_thread = true;
+        }
+
+        if index == args.len() && !options.init && !options.list_backends && !options.help {
+            bail!("Missing the wasm module path");
+        }
+        Ok((options, &args[index..]))
+    }
+
+    fn engine_config(&self) -> Config {
+        let mut config = Config::default();
+        if let Some(max_wasm_stack) = self.max_wasm_stack {
+            config.max_wasm_stack(max_wasm_stack);
+        }
+        if self.pooling {
+            let instances = self.pooling_instances.unwrap_or(DEFAULT_POOLING_INSTANCES);
+            let mut pooling_config = PoolingAllocationConfig::default();
+            pooling_config
+                .total_core_instances(instances)
+                .total_memories(instances)
+                .total_tables(instances)
+                .max_memory_size(self.pooling_max_memory.unwrap_or(DEFAULT_POOLING_MAX_MEMORY));
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config));
+        }
+        config
+    }
+
+    /// The host options of a child process of `--processes`, so that every child measures the
+    /// configuration that was asked for. The model and image are already in the module
+    /// arguments, and the options that only make sense for the parent are left out, like
+    /// `--compare-timing`, whose file all children would write at once.
+    fn child_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let flags = [
+            (self.no_cache_write, "--no-cache-write"),
+            (self.pooling, "--pooling"),
+            (self.single_thread, "--single-thread"),
+            (self.openvino, "--openvino"),
+            (self.flush_denormals, "--flush-denormals"),
+            (self.compare_denormals, "--compare-denormals"),
+        ];
+        for (enabled, flag) in flags.iter() {
+            if *enabled {
+                args.push(flag.to_string());
+            }
+        }
+        let values = [
+            ("--max-wasm-stack", self.max_wasm_stack.map(|value| value.to_string())),
+            ("--pooling-instances", self.pooling_instances.map(|value| value.to_string())),
+            ("--pooling-max-memory", self.pooling_max_memory.map(|value| value.to_string())),
+            ("--instantiations", self.instantiations.map(|value| value.to_string())),
+        ];
+        for (flag, value) in values.iter() {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        args
     }
 }
 
+const DEFAULT_POOLING_INSTANCES: u32 = 16;
+// the pooling allocator defaults to 10 MiB memories, too small for decoding an image
+const DEFAULT_POOLING_MAX_MEMORY: usize = 1 << 30;
+
+/// Instantiates the module `count` times, each time in a new store as a serverless host would,
+/// and returns the mean instantiation time.
+fn measure_instantiation(
+    engine: &Engine,
+    linker: &Linker<Ctx>,
+    module: &Module,
+    count: usize,
+    directories: &Vec<&str>,
+    module_args: &[String],
+    host_options: &HostOptions,
+) -> Result<Duration> {
+    let instance_pre = linker.instantiate_pre(module)?;
+    let mut total = Duration::ZERO;
+    for _ in 0..count {
+        let mut store = Store::new(engine, Ctx::new(directories, module_args, host_options)?);
+        let start = Instant::now();
+        instance_pre.instantiate(&mut store)?;
+        total += start.elapsed();
+    }
+    Ok(total / count.max(1) as u32)
+}
+
+/// The flush-to-zero and denormals-are-zero bits of the MXCSR register.
+#[cfg(target_arch = "x86_64")]
+const FTZ_DAZ: u32 = 0x8040;
+
+/// Sets the flush-to-zero and denormals-are-zero flags of the calling thread on or off and
+/// returns whether the thread now has the requested setting. Only x86-64 has these flags.
+#[cfg(target_arch = "x86_64")]
+#[allow(deprecated)]
+fn set_flush_denormals(enabled: bool) -> bool {
+    use std::arch::x86_64::{_mm_getcsr, _mm_setcsr};
+    unsafe {
+        let csr = _mm_getcsr();
+        _mm_setcsr(if enabled { csr | FTZ_DAZ } else { csr & !FTZ_DAZ });
+        (_mm_getcsr() & FTZ_DAZ == FTZ_DAZ) == enabled
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn set_flush_denormals(enabled: bool) -> bool {
+    !enabled
+}
+
+/// Instantiates the module in a new store and times one call into it, for a second run next to
+/// the main one.
+fn time_call(
+    engine: &Engine,
+    linker: &Linker<Ctx>,
+    module: &Module,
+    function_name: &str,
+    directories: &Vec<&str>,
+    module_args: &[String],
+    host_options: &HostOptions,
+) -> Result<Duration> {
+    let mut store = Store::new(engine, Ctx::new(directories, module_args, host_options)?);
+    let instance = linker.instantiate_pre(module)?.instantiate(&mut store)?;
+    let function = instance.get_typed_func::<(), ()>(&mut store, function_name)?;
+    let start = Instant::now();
+    function.call(&mut store, ())?;
+    Ok(start.elapsed())
+}
+
+/// Reports why the call into the module failed and returns the exit code of the host: the
+/// module's own exit status if it called `exit`, and 1 for a trap or another error.
+fn report_call_error(error: &anyhow::Error) -> i32 {
+    if let Some(exit) = error.downcast_ref::<I32Exit>() {
+        reportln!("Module exited with status {}", exit.0);
+        return exit.0;
+    }
+
+    reportln!("============= Trap =============");
+    match error.downcast_ref::<Trap>() {
+        Some(trap) => reportln!("Trap code: {:?} ({})", trap, trap),
+        None => reportln!("Trap code: none, the call failed with an error"),
+    }
+    reportln!("Error: {:#}", error);
+    match error.downcast_ref::<WasmBacktrace>() {
+        Some(backtrace) => reportln!("Wasm backtrace:\n{}", backtrace),
+        None => reportln!("Wasm backtrace: not captured"),
+    }
+    reportln!("=======================================");
+    1
+}
+
+/// The times the module wrote to `path` with `--guest-timing`, by their key.
+fn read_guest_timing(path: &str) -> Result<HashMap<String, Duration>> {
+    let mut guest_times = HashMap::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        if let Some((key, value)) = line.split_once(' ') {
+            guest_times.insert(key.to_string(), Duration::from_nanos(value.parse()?));
+        }
+    }
+    Ok(guest_times)
+}
+
+/// Prints the time measured around the call into the module next to the times the module
+/// measured itself. The difference between the call and the module's own total is the cost of
+/// crossing into the module and of everything the module doesn't track.
+fn print_timing_comparison(host_call: Duration, guest_times: &HashMap<String, Duration>) {
+    let guest_total = guest_times.get("total_ns").cloned().unwrap_or_default();
+    let guest_inference = guest_times.get("inference_ns").cloned().unwrap_or_default();
+
+    reportln!("============= Host vs Guest Timing =============");
+    reportln!("Host call: {:?}", host_call);
+    reportln!("Guest total: {:?}", guest_total);
+    reportln!("Guest inference: {:?}", guest_inference);
+    reportln!("Difference (host call - guest total): {:?}", host_call.saturating_sub(guest_total));
+    reportln!("=======================================");
+}
+
+/// Writes the serialized module to `path` and returns whether it did. The cache is only an
+/// optimization, so a failed write is a warning and must not abort the run.
+fn write_module_cache(path: &str, bytes: &[u8]) -> bool {
+    match std::fs::write(path, bytes) {
+        WasmtimeResultOk(()) => true,
+        Err(error) => {
+            eprintln!("Warning: could not write {}: {}, continuing without the cache", path, error);
+            false
+        }
+    }
+}
+
+/// Finds the file called `name`, with or without its extension, in `dir` and returns its path
+/// as the module sees it. Fails with the names available in `dir` if there is no such file.
+fn resolve_asset(dir: &str, name: &str) -> Result<String> {
+    let mut available = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or_default();
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        if file_name == name || stem == name {
+            // the directories are preopened under the same relative path
+            return Ok(format!("/{}/{}", dir, file_name));
+        }
+        available.push(stem.to_string());
+    }
+    available.sort();
+    bail!("Unknown name {} in {}, available: {}", name, dir, available.join(", "))
+}
+
+/// Creates the directories shared with the module and tells where the module looks for its
+/// inputs.
+fn init_directories(directories: &[&str]) -> Result<()> {
+    for dir in directories {
+        std::fs::create_dir_all(dir)?;
+        println!("Created: {}", dir);
+    }
+    println!("Place the ONNX model at assets/models/mobilenetv2-10.onnx");
+    println!("Place the input image at assets/imgs/unseen_dog.jpg");
+    println!("Place the ImageNet class names, one per line, at assets/labels/imagenet_classes.txt");
+    Ok(())
+}
+
+/// The names of the wasi-nn backends compiled into this host, followed by whether the ONNX
+/// backend, the one the module uses, can run models on the GPU.
+fn backend_list() -> Vec<String> {
+    let mut lines: Vec<String> = wasmtime_wasi_nn::backend::list()
+        .iter()
+        // the encodings debug-print with their type, as in `GraphEncoding::Onnx`
+        .map(|backend| {
+            let encoding = format!("{:?}", backend.encoding());
+            encoding.rsplit("::").next().unwrap_or_default().to_lowercase()
+        })
+        .collect();
+    if OnnxBackend::supports_target(ExecutionTarget::Gpu) {
+        lines.push("GPU: available".to_string());
+    } else {
+        lines.push("GPU: not available (CPU-only build)".to_string());
+    }
+    lines
+}
+
+/// Prints the wasi-nn backends compiled into this host.
+fn list_backends() {
+    println!("============= wasi-nn Backends =============");
+    for line in backend_list() {
+        println!("{}", line);
+    }
+    println!("=======================================");
+}
+
+/// Fails with a hint to run `--init` if any of the directories shared with the module is
+/// missing, since preopening it would fail with a much less helpful error.
+fn check_directories(directories: &[&str]) -> Result<()> {
+    let missing: Vec<&str> = directories
+        .iter()
+        .filter(|dir| !Path::new(dir).is_dir())
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        bail!("Missing directories: {}. Run with --init to create them", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Runs `processes` copies of `executable` with the given arguments at the same time and
+/// returns the output and wall-clock time of each, or why it couldn't be run.
+fn run_processes(processes: usize, executable: &Path, args: &[String]) -> Vec<Result<(std::process::Output, Duration)>> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..processes)
+            .map(|_| {
+                scope.spawn(move || {
+                    let process_start = Instant::now();
+                    let output = Command::new(executable)
+                        .args(args)
+                        .stdout(Stdio::piped())
+                        .output()?;
+                    Ok((output, process_start.elapsed()))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("the thread running the process panicked"))))
+            .collect()
+    })
+}
+
+/// Runs `processes` copies of this executable with the given host and module arguments at the
+/// same time, then reports the wall-clock time of each process and the aggregate throughput.
+fn run_concurrent(processes: usize, host_args: &[String], module_args: &[String]) -> Result<()> {
+    let executable = env::current_exe()?;
+    let args: Vec<String> = host_args.iter().chain(module_args).cloned().collect();
+    let start = Instant::now();
+    let results = run_processes(processes, &executable, &args);
+    let total = start.elapsed();
+
+    let mut succeeded = 0;
+    for (index, result) in results.into_iter().enumerate() {
+        println!("============= Process {} =============", index + 1);
+        // a process that couldn't be run counts as failed, the others are still reported
+        let (output, wall_clock_time) = match result {
+            WasmtimeResultOk(result) => result,
+            Err(error) => {
+                println!("Error: {}", error);
+                continue;
+            }
+        };
+        print!("{}", String::from_utf8_lossy(&output.stdout));
+        println!("Status: {}", output.status);
+        println!("Wall Clock Time: {:?}", wall_clock_time);
+        if output.status.success() {
+            succeeded += 1;
+        }
+    }
+
+    println!("============= Concurrent Runs =============");
+    println!("Processes: {} ({} succeeded)", processes, succeeded);
+    println!("Wall Clock Time: {:?}", total);
+    println!("Throughput: {:.3} runs/s", succeeded as f64 / total.as_secs_f64());
+    println!("=======================================");
+
+    Ok(())
+}
 
 fn main() -> wasmtime::Result<()> {
     const MODEL_DIR: &str = "assets/models";
     const IMAGE_DIR: &str = "assets/imgs";
-    let shared_dirs: Vec<&str> = vec![MODEL_DIR, IMAGE_DIR];
+    // writable directory for the exports produced by the module
+    const RESULTS_DIR: &str = "results";
+    // the class names are optional, so their directory is only shared when it exists
+    const LABELS_DIR: &str = "assets/labels";
+    let mut shared_dirs: Vec<&str> = vec![MODEL_DIR, IMAGE_DIR, RESULTS_DIR];
+    if Path::new(LABELS_DIR).is_dir() {
+        shared_dirs.push(LABELS_DIR);
+    }
 
     let args: Vec<String> = env::args().collect();
-    // if args.len() != 5 {
-    //     println!("Usage: {} <wasm module> <model> <image> <number of repeats>", args[0]);
-    //     return Ok(());
-    // }
-
-    let wasm_module_filename: &str = &args[1];
-    // let model_filename: &str = &args[2];
-    // let image_name: &str = &args[3];
-    // let model_index = match get_model_index(model_filename) {
-    //     Some(index) => index,
-    //     None => {
-    //         println!("Model not found: {}", model_filename);
-    //         return Ok(());
-    //     }
-    // };
-    // let image_index = match get_image_index(image_name) {
-    //     Some(index) => index,
-    //     None => {
-    //         println!("Image not found: {}", image_name);
-    //         return Ok(());
-    //     }
-    // };
-    // let repeats: u32 = args[4].parse().unwrap();
-
-    let config = Config::default();
+    let (host_options, module_args) = match HostOptions::parse(&args[1..]) {
+        WasmtimeResultOk(parsed) => parsed,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if host_options.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    if host_options.init {
+        return init_directories(&[MODEL_DIR, IMAGE_DIR, RESULTS_DIR, LABELS_DIR]);
+    }
+    if host_options.list_backends {
+        list_backends();
+        return Ok(());
+    }
+
+    // the module reads its model, image and repeats from its WASI arguments, so the selections
+    // made on the host are appended to them, also for the concurrent copies
+    let mut guest_args: Vec<String> = module_args.to_vec();
+    if let Some(model) = &host_options.model {
+        guest_args.push("--model".to_string());
+        guest_args.push(resolve_asset(MODEL_DIR, model)?);
+    }
+    if let Some(image) = &host_options.image {
+        guest_args.push("--image".to_string());
+        guest_args.push(resolve_asset(IMAGE_DIR, image)?);
+    }
+    if let Some(processes) = host_options.processes {
+        return run_concurrent(processes, &host_options.child_args(), &guest_args);
+    }
+
+    const GUEST_TIMING_FILE: &str = "results/guest_timing.txt";
+    if host_options.compare_timing {
+        guest_args.push("--guest-timing".to_string());
+        guest_args.push(GUEST_TIMING_FILE.to_string());
+    }
+    let module_args: &[String] = &guest_args;
+    JSON_OUTPUT.store(
+        module_args.windows(2).any(|pair| pair[0] == "--format" && pair[1] == "json"),
+        Ordering::Relaxed
+    );
+
+    // the module sees its own filename followed by the remaining arguments
+    let wasm_module_filename: &str = &module_args[0];
+    reportln!("Module arguments: {}", module_args[1..].join(" "));
+
+    let config = host_options.engine_config();
     let engine = Engine::new(&config)?;
+    match host_options.max_wasm_stack {
+        Some(max_wasm_stack) => reportln!("Max wasm stack: {} bytes", max_wasm_stack),
+        None => reportln!("Max wasm stack: wasmtime default"),
+    }
+    if host_options.single_thread {
+        reportln!("ONNX threads: 1 intra-op, 1 inter-op");
+    } else {
+        reportln!("ONNX threads: runtime default");
+    }
     let mut linker = wasmtime::Linker::new(&engine);
 
     wasi_common::sync::add_to_linker(&mut linker, |host: &mut Ctx| &mut host.wasi)?;
     wasmtime_wasi_nn::witx::add_to_linker(&mut linker, |host| &mut host.wasi_nn)?;
+    add_getrusage_to_linker(&mut linker)?;
 
+    std::fs::create_dir_all(RESULTS_DIR)?;
+    check_directories(&shared_dirs)?;
     let mut store = Store::new(
         &engine,
-        Ctx::new(&shared_dirs)?
+        Ctx::new(&shared_dirs, module_args, &host_options)?
     );
 
     let wasm_module_serialized_name = wasm_module_filename.to_string() + ".SERIALIZED";
+    let (rss_before_load, peak_rss_before_load) = rss_bytes();
+    let mut loaded_from_cache = true;
     let wasm_module =
         match unsafe { Module::deserialize_file(&engine, wasm_module_serialized_name.clone()) } {
             WasmtimeResultOk(serialized_module) => serialized_module,
             Err(_) => {
+                loaded_from_cache = false;
                 let loaded_module = Module::from_file(&engine, wasm_module_filename)?;
-                let byte_module = loaded_module.serialize()?;
-                std::fs::write(wasm_module_serialized_name, byte_module).unwrap();
+                if !host_options.no_cache_write {
+                    write_module_cache(&wasm_module_serialized_name, &loaded_module.serialize()?);
+                }
 
                 loaded_module
             }
         };
 
+    // deserializing the cache and compiling the wasm file have different memory profiles
+    let (rss_after_load, peak_rss_after_load) = rss_bytes();
+    reportln!("============= Module Load Memory =============");
+    reportln!("Source: {}", if loaded_from_cache { "serialized cache" } else { "wasm file" });
+    reportln!("RSS delta: {} bytes", rss_after_load as i64 - rss_before_load as i64);
+    reportln!("Peak RSS delta: {} bytes", peak_rss_after_load - peak_rss_before_load);
+    reportln!("=======================================");
+
+    if let Some(instantiations) = host_options.instantiations {
+        let mean = measure_instantiation(&engine, &linker, &wasm_module, instantiations, &shared_dirs, module_args, &host_options)?;
+        reportln!("============= Instantiation =============");
+        reportln!("Allocator: {}", if host_options.pooling { "pooling" } else { "on-demand" });
+        reportln!("Instantiations: {}", instantiations);
+        reportln!("Mean instantiation time: {:?}", mean);
+        reportln!("=======================================");
+    }
+
     // add the module to the linker
     const MODULE_NAME: &str = "test";
     const FUNCTION_NAME: &str = "main";
@@ -108,7 +766,302 @@ fn main() -> wasmtime::Result<()> {
         .into_func().unwrap()
         .typed::<(), ()>(&mut store).unwrap();
 
-    let _result = inference_function.call(&mut store, ());
+    if host_options.flush_denormals && !set_flush_denormals(true) {
+        bail!("Flushing denormals to zero is only supported on x86-64");
+    }
+
+    let call_start = Instant::now();
+    let result = inference_function.call(&mut store, ());
+    let host_call = call_start.elapsed();
+
+    if let Err(error) = result {
+        std::process::exit(report_call_error(&error));
+    }
+
+    reportln!("{}", store.data().getrusage_summary(host_call));
+    // read before the second call of --compare-denormals, which rewrites the file
+    let guest_times = if host_options.compare_timing {
+        Some(read_guest_timing(GUEST_TIMING_FILE)?)
+    } else {
+        None
+    };
+
+    if host_options.compare_denormals {
+        let flushed = !host_options.flush_denormals;
+        if !set_flush_denormals(flushed) {
+            bail!("Flushing denormals to zero is only supported on x86-64");
+        }
+        let other_call = time_call(&engine, &linker, &wasm_module, FUNCTION_NAME, &shared_dirs, module_args, &host_options)?;
+        set_flush_denormals(false);
+
+        let (flushed_call, kept_call) = if flushed { (other_call, host_call) } else { (host_call, other_call) };
+        reportln!("============= Denormals =============");
+        reportln!("Denormals kept: {:?}", kept_call);
+        reportln!("Denormals flushed to zero: {:?}", flushed_call);
+        reportln!("Speedup from flushing: {:.3}x", kept_call.as_secs_f64() / flushed_call.as_secs_f64());
+        reportln!("=======================================");
+    }
+
+    if let Some(guest_times) = guest_times {
+        print_timing_comparison(host_call, &guest_times);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module that calls the getrusage shim `calls` times from its `main`.
+    fn getrusage_module(engine: &Engine, calls: usize) -> Result<Module> {
+        let call = "(drop (call $getrusage (i32.const 0)))";
+        let wat = format!(
+            r#"(module
+                (import "benchmark" "getrusage" (func $getrusage (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "main") {}))"#,
+            vec![call; calls].join(" ")
+        );
+        Module::new(engine, wat::parse_str(&wat)?)
+    }
+
+    #[test]
+    fn getrusage_shim_time_is_recorded() -> Result<()> {
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        add_getrusage_to_linker(&mut linker)?;
+        let module = getrusage_module(&engine, 5)?;
+        let mut store = Store::new(&engine, Ctx::new(&vec![], &[], &HostOptions::default())?);
+
+        let instance = linker.instantiate(&mut store, &module)?;
+        let main = instance.get_typed_func::<(), ()>(&mut store, "main")?;
+        let call_start = Instant::now();
+        main.call(&mut store, ())?;
+        let host_call = call_start.elapsed();
+
+        let ctx = store.data();
+        assert_eq!(ctx.getrusage_calls, 5);
+        assert!(ctx.getrusage_time > Duration::ZERO);
+        assert!(ctx.getrusage_time <= host_call);
+        assert!(ctx.getrusage_summary(host_call).starts_with("getrusage shim: 5 calls"));
+        Ok(())
+    }
+
+    #[test]
+    fn host_call_covers_the_guest_timing() -> Result<()> {
+        // the module times a loop with the WASI clock, as the benchmark does around inference
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        wasi_common::sync::add_to_linker(&mut linker, |host: &mut Ctx| &mut host.wasi)?;
+        let module = Module::new(&engine, wat::parse_str(r#"(module
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func $clock_time_get (param i32 i64 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "main") (local $i i32)
+                (drop (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 0)))
+                (loop $spin
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $spin (i32.lt_u (local.get $i) (i32.const 1000000))))
+                (drop (call $clock_time_get (i32.const 1) (i64.const 1) (i32.const 8)))))"#)?)?;
+        let mut store = Store::new(&engine, Ctx::new(&vec![], &[], &HostOptions::default())?);
+        let instance = linker.instantiate(&mut store, &module)?;
+        let main = instance.get_typed_func::<(), ()>(&mut store, "main")?;
+
+        let call_start = Instant::now();
+        main.call(&mut store, ())?;
+        let host_call = call_start.elapsed();
+
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let (mut start, mut end) = ([0u8; 8], [0u8; 8]);
+        memory.read(&store, 0, &mut start)?;
+        memory.read(&store, 8, &mut end)?;
+        let (start, end) = (u64::from_le_bytes(start), u64::from_le_bytes(end));
+        let path = env::temp_dir().join(format!("guest_timing_{}.txt", std::process::id()));
+        std::fs::write(&path, format!("total_ns {}\ninference_ns {}\n", end - start, end - start))?;
+
+        let guest_times = read_guest_timing(path.to_str().unwrap())?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(guest_times.len(), 2);
+        assert!(guest_times["inference_ns"] > Duration::ZERO);
+        assert!(host_call >= guest_times["inference_ns"]);
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    #[allow(deprecated)]
+    fn flush_denormals_round_trips_through_mxcsr() -> Result<()> {
+        use std::arch::x86_64::_mm_getcsr;
+        // the module keeps halving a small float through the denormal range
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        wasi_common::sync::add_to_linker(&mut linker, |host: &mut Ctx| &mut host.wasi)?;
+        let module = Module::new(&engine, wat::parse_str(r#"(module
+            (func (export "main") (local $x f32) (local $i i32)
+                (local.set $x (f32.const 1e-30))
+                (loop $halve
+                    (local.set $x (f32.mul (local.get $x) (f32.const 0.5)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $halve (i32.lt_u (local.get $i) (i32.const 10000))))))"#)?)?;
+        let directories = vec![];
+        let options = HostOptions::default();
+
+        for &enabled in [true, false].iter() {
+            assert!(set_flush_denormals(enabled));
+            assert_eq!(unsafe { _mm_getcsr() } & FTZ_DAZ, if enabled { FTZ_DAZ } else { 0 });
+            let call = time_call(&engine, &linker, &module, "main", &directories, &[], &options)?;
+            assert!(call > Duration::ZERO);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_processes_are_all_collected() {
+        let args = vec!["result".to_string()];
+        let results = run_processes(2, Path::new("echo"), &args);
+        assert_eq!(results.len(), 2);
+        for result in results {
+            let (output, _) = result.unwrap();
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "result\n");
+        }
+
+        let results = run_processes(2, Path::new("/nonexistent/wasmtime-test"), &args);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.is_err()));
+    }
+
+    #[test]
+    fn children_do_not_compare_timing() {
+        let options = HostOptions { compare_timing: true, pooling: true, ..HostOptions::default() };
+        assert_eq!(options.child_args(), vec!["--pooling".to_string()]);
+    }
+
+    #[test]
+    fn backend_list_has_onnx() {
+        let lines = backend_list();
+        assert!(lines.iter().any(|line| line == "onnx"));
+        assert_eq!(lines.last().unwrap(), "GPU: not available (CPU-only build)");
+    }
+
+    /// A fresh directory under the system temp directory.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("wasmtime-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn assets_resolve_with_or_without_their_extension() -> Result<()> {
+        let dir = test_dir("assets");
+        std::fs::write(dir.join("mobilenetv2-10.onnx"), "")?;
+        std::fs::write(dir.join("squeezenet.onnx"), "")?;
+        let dir = dir.to_str().unwrap();
+
+        let expected = format!("/{}/mobilenetv2-10.onnx", dir);
+        assert_eq!(resolve_asset(dir, "mobilenetv2-10")?, expected);
+        assert_eq!(resolve_asset(dir, "mobilenetv2-10.onnx")?, expected);
+
+        let error = resolve_asset(dir, "resnet50").unwrap_err().to_string();
+        assert!(error.contains("Unknown name resnet50"), "{}", error);
+        assert!(error.ends_with("available: mobilenetv2-10, squeezenet"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn raised_max_wasm_stack_allows_deeper_recursion() -> Result<()> {
+        // recursing this deep overflows the default 512 KiB wasm stack
+        fn recurse(options: HostOptions) -> Result<i32> {
+            let engine = Engine::new(&options.engine_config())?;
+            let module = Module::new(&engine, wat::parse_str(r#"(module
+                (func $recurse (export "recurse") (param $depth i32) (result i32)
+                    (if (result i32) (i32.eqz (local.get $depth))
+                        (then (i32.const 0))
+                        (else (i32.add (i32.const 1)
+                            (call $recurse (i32.sub (local.get $depth) (i32.const 1))))))))"#)?)?;
+            let mut store = Store::new(&engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[])?;
+            let recurse = instance.get_typed_func::<i32, i32>(&mut store, "recurse")?;
+            recurse.call(&mut store, 100_000)
+        }
+        // the wasm stack lives on the native stack of the calling thread
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || -> Result<()> {
+                let error = recurse(HostOptions::default()).unwrap_err();
+                assert_eq!(error.downcast_ref::<Trap>(), Some(&Trap::StackOverflow));
+                let options = HostOptions { max_wasm_stack: Some(32 << 20), ..HostOptions::default() };
+                assert_eq!(recurse(options)?, 100_000);
+                Ok(())
+            })?
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn init_creates_the_shared_directories() -> Result<()> {
+        let dir = test_dir("init");
+        let models = dir.join("assets/models");
+        let results = dir.join("results");
+        // creating a directory that already exists is fine
+        std::fs::create_dir_all(&results)?;
+
+        init_directories(&[models.to_str().unwrap(), results.to_str().unwrap()])?;
+        assert!(models.is_dir());
+        assert!(results.is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn pooling_allocator_limits_live_instances() -> Result<()> {
+        let options = HostOptions { pooling: true, pooling_instances: Some(2), ..HostOptions::default() };
+        let engine = Engine::new(&options.engine_config())?;
+        let module = Module::new(&engine, wat::parse_str("(module (memory 1))")?)?;
+
+        let mut stores: Vec<Store<()>> = (0..3).map(|_| Store::new(&engine, ())).collect();
+        wasmtime::Instance::new(&mut stores[0], &module, &[])?;
+        wasmtime::Instance::new(&mut stores[1], &module, &[])?;
+        // both slots are taken until one of the stores is dropped
+        assert!(wasmtime::Instance::new(&mut stores[2], &module, &[]).is_err());
+        stores.remove(0);
+        wasmtime::Instance::new(&mut stores[1], &module, &[])?;
+        Ok(())
+    }
+
+    #[test]
+    fn trapping_module_reports_its_trap_code() -> Result<()> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat::parse_str(
+            r#"(module (func (export "main") unreachable))"#)?)?;
+        let mut store = Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[])?;
+        let main = instance.get_typed_func::<(), ()>(&mut store, "main")?;
+
+        let error = main.call(&mut store, ()).unwrap_err();
+        assert_eq!(error.downcast_ref::<Trap>(), Some(&Trap::UnreachableCodeReached));
+        assert!(error.downcast_ref::<WasmBacktrace>().is_some());
+        assert_eq!(report_call_error(&error), 1);
+
+        // a module that calls `exit` isn't a trap and keeps its status
+        assert_eq!(report_call_error(&anyhow::Error::new(I32Exit(3))), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_cache_write_does_not_abort() -> Result<()> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat::parse_str("(module)")?)?;
+        let bytes = module.serialize()?;
+        let dir = test_dir("cache");
+
+        let missing = dir.join("missing/module.wasm.SERIALIZED");
+        assert!(!write_module_cache(missing.to_str().unwrap(), &bytes));
+
+        let path = dir.join("module.wasm.SERIALIZED");
+        assert!(write_module_cache(path.to_str().unwrap(), &bytes));
+        unsafe { Module::deserialize_file(&engine, &path)? };
+        Ok(())
+    }
+}
@@ -13,10 +13,25 @@ use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 #[derive(Default)]
-pub struct OnnxBackend();
+pub struct OnnxBackend {
+    single_thread: bool,
+}
 unsafe impl Send for OnnxBackend {}
 unsafe impl Sync for OnnxBackend {}
 
+impl OnnxBackend {
+    /// A backend whose sessions run on one intra-op and one inter-op thread, for measurements
+    /// that shouldn't depend on the number of cores.
+    pub fn single_threaded() -> Self {
+        Self { single_thread: true }
+    }
+
+    /// Whether models can be loaded for `target`. Sessions are built without any execution
+    /// provider, so that is only the CPU.
+    pub fn supports_target(target: ExecutionTarget) -> bool {
+        matches!(target, ExecutionTarget::Cpu)
+    }
+}
 impl BackendInner for OnnxBackend {
     fn encoding(&self) -> GraphEncoding {
         GraphEncoding::Onnx
@@ -26,10 +41,22 @@ impl BackendInner for OnnxBackend {
         if builders.len() != 1 {
             return Err(BackendError::InvalidNumberOfBuilders(1, builders.len()).into());
         }
+        // fail rather than quietly running a GPU or TPU request on the CPU
+        if !Self::supports_target(target) {
+            return Err(BackendError::BackendAccess(anyhow::anyhow!(
+                "the ONNX backend only supports the CPU execution target, not {:?}",
+                target
+            )));
+        }
 
-        let session = Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .commit_from_memory(builders[0])?;
+        let mut session_builder =
+            Session::builder()?.with_optimization_level(GraphOptimizationLevel::Level3)?;
+        if self.single_thread {
+            session_builder = session_builder
+                .with_intra_threads(1)?
+                .with_inter_threads(1)?;
+        }
+        let session = session_builder.commit_from_memory(builders[0])?;
 
         let box_: Box<dyn BackendGraph> =
             Box::new(ONNXGraph(Arc::new(Mutex::new(session)), target));
@@ -87,30 +114,29 @@ impl BackendExecutionContext for ONNXExecutionContext {
     }
 
     fn compute(&mut self) -> Result<(), BackendError> {
-        let shaped_inputs: Vec<_> = self
-            .inputs
-            .iter()
-            .enumerate()
-            .map(|(i, _o)| {
-                let input = self.inputs[i].as_ref().unwrap();
-                let dims = input
-                    .dimensions
-                    .as_slice()
-                    .iter()
-                    .map(|d| *d as i64)
-                    .collect::<Vec<_>>();
-                match input.tensor_type {
-                    TensorType::Fp32 => {
-                        let data = bytes_to_f32_vec(input.data.to_vec());
-                        inputs![(dims, Arc::new(data.into_boxed_slice()))].unwrap()
-                    }
-                    _ => {
-                        unimplemented!("{:?} not supported by ONNX", input.tensor_type);
-                    }
+        let mut shaped_inputs = Vec::with_capacity(self.inputs.len());
+        for input in self.inputs.iter() {
+            let input = input.as_ref().unwrap();
+            let dims = input
+                .dimensions
+                .as_slice()
+                .iter()
+                .map(|d| *d as i64)
+                .collect::<Vec<_>>();
+            match input.tensor_type {
+                TensorType::Fp32 => {
+                    let data = bytes_to_f32_vec(input.data.to_vec());
+                    shaped_inputs.extend(inputs![(dims, Arc::new(data.into_boxed_slice()))]?);
+                }
+                // an error for the guest rather than a panic of the host
+                _ => {
+                    return Err(BackendError::BackendAccess(anyhow::anyhow!(
+                        "{:?} tensors are not supported by the ONNX backend, only Fp32",
+                        input.tensor_type
+                    )));
                 }
-            })
-            .flatten()
-            .collect();
+            }
+        }
 
         let session = self.session.lock().unwrap();
         let res = session.run(shaped_inputs.as_slice())?;
@@ -124,6 +150,10 @@ impl BackendExecutionContext for ONNXExecutionContext {
 
     fn get_output(&mut self, index: u32, destination: &mut [u8]) -> Result<u32, BackendError> {
         let output = self.outputs[index as usize].as_ref().unwrap();
+        if output.len() > destination.len() {
+            return Err(BackendError::NotEnoughMemory(output.len()));
+        }
+
         destination[..output.len()].copy_from_slice(output);
         Ok(output.len() as u32)
     }
@@ -13,6 +13,7 @@
 //!
 //! [`types`]: crate::wit::types
 
+use crate::backend::BackendError;
 use crate::ctx::{UsageError, WasiNnCtx, WasiNnError, WasiNnResult as Result};
 use wiggle::{GuestMemory, GuestPtr};
 
@@ -42,7 +43,10 @@ mod gen {
         ) -> anyhow::Result<types::NnErrno> {
             tracing::debug!("host error: {:?}", e);
             match e {
-                WasiNnError::BackendError(_) => unimplemented!(),
+                WasiNnError::BackendError(BackendError::NotEnoughMemory(_)) => {
+                    Ok(types::NnErrno::TooLarge)
+                }
+                WasiNnError::BackendError(_) => Ok(types::NnErrno::RuntimeError),
                 WasiNnError::GuestError(_) => unimplemented!(),
                 WasiNnError::UsageError(_) => unimplemented!(),
             }